@@ -3,14 +3,26 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::{parse, Ident, Item};
 
+/// Label a test case, `#[kern_test(leak_check)]` also
+/// fails the test if it leaves anything allocated, this
+/// expects `assert_no_leaks` to be in scope
 #[proc_macro_attribute]
-pub fn kern_test(_attr: TokenStream, tokens: TokenStream) -> TokenStream {
+pub fn kern_test(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let leak_check = if attr.is_empty() {
+        false
+    } else {
+        let attr: Ident = parse(attr).expect("failed to parse kern_test arguments");
+        if attr != "leak_check" {
+            panic!("unknown kern_test argument {}", attr);
+        }
+        true
+    };
     let two: Item = parse(tokens).expect("failed to parse tokens");
-    let ret = insert_logging(two);
+    let ret = insert_logging(two, leak_check);
     ret.into()
 }
 
-fn insert_logging(tokens: Item) -> proc_macro2::TokenStream {
+fn insert_logging(tokens: Item, leak_check: bool) -> proc_macro2::TokenStream {
     let f = match tokens {
         Item::Fn(f) => f,
         _ => panic!("kern_test can only label fns"),
@@ -18,13 +30,18 @@ fn insert_logging(tokens: Item) -> proc_macro2::TokenStream {
     let orig = f.sig.ident.clone();
     let name = format!("{}", f.sig.ident.clone());
     let name2 = Ident::new(&format!("{}_", name), proc_macro2::Span::call_site());
+    let call = if leak_check {
+        quote! { assert_no_leaks(#orig); }
+    } else {
+        quote! { #orig(); }
+    };
     quote! {
         #[test_case]
         fn #name2() {
             #f
             x86_64::instructions::interrupts::without_interrupts(|| {
                 serial_print!("running {}... ", #name);
-                #orig();
+                #call
                 serial_println!("[ok]");
             });
         }
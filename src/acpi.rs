@@ -0,0 +1,167 @@
+//! Just enough ACPI to find the interrupt controllers,
+//! tables are read through the physical memory mapping
+use crate::{error::Error, memory};
+use core::ptr::read_unaligned;
+use x86_64::PhysAddr;
+
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+
+const SDT_HEADER_LEN: u64 = 36;
+
+/// An IOAPIC described by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: PhysAddr,
+    /// The first global system interrupt this
+    /// IOAPIC handles
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't wired to the
+/// global system interrupt of the same number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// The MPS INTI flags, polarity in bits 0-1
+    /// and trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The parts of the Multiple APIC Description Table we use
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+    /// The number of enabled processors
+    pub cpus: usize,
+}
+
+impl Madt {
+    /// Find and parse the MADT
+    pub fn find() -> Result<Self, Error> {
+        let rsdp = find_rsdp()?;
+        let madt = find_table(rsdp, b"APIC")?;
+        Ok(unsafe { Self::parse(madt) })
+    }
+
+    /// The global system interrupt ISA `irq` is wired to
+    pub fn isa_override(&self, irq: u8) -> Option<InterruptOverride> {
+        self.overrides.iter().flatten().find(|o| o.irq == irq).copied()
+    }
+
+    unsafe fn parse(table: PhysAddr) -> Self {
+        let len = u64::from(read::<u32>(table + 4u64));
+        let mut madt = Self {
+            local_apic: PhysAddr::new(u64::from(read::<u32>(table + SDT_HEADER_LEN))),
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+            cpus: 0,
+        };
+        let (mut io_apics, mut overrides) = (0, 0);
+        let mut entry = table + SDT_HEADER_LEN + 8u64;
+        while entry < table + len {
+            let kind = read::<u8>(entry);
+            let entry_len = read::<u8>(entry + 1u64);
+            match kind {
+                // processor local APIC, bit 0 of the flags is enabled
+                0 if read::<u32>(entry + 4u64) & 1 == 1 => madt.cpus += 1,
+                1 if io_apics < MAX_IO_APICS => {
+                    madt.io_apics[io_apics] = Some(IoApicInfo {
+                        id: read(entry + 2u64),
+                        addr: PhysAddr::new(u64::from(read::<u32>(entry + 4u64))),
+                        gsi_base: read(entry + 8u64),
+                    });
+                    io_apics += 1;
+                }
+                2 if overrides < MAX_OVERRIDES => {
+                    madt.overrides[overrides] = Some(InterruptOverride {
+                        irq: read(entry + 3u64),
+                        gsi: read(entry + 4u64),
+                        flags: read(entry + 8u64),
+                    });
+                    overrides += 1;
+                }
+                // 64 bit local APIC address override
+                5 => madt.local_apic = PhysAddr::new(read(entry + 4u64)),
+                _ => (),
+            }
+            if entry_len == 0 {
+                break;
+            }
+            entry += u64::from(entry_len);
+        }
+        madt
+    }
+}
+
+unsafe fn read<T>(addr: PhysAddr) -> T {
+    let virt = memory::phys_to_virt(addr).expect("memory::init has not been called");
+    read_unaligned(virt.as_ptr())
+}
+
+fn checksum(addr: PhysAddr, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(unsafe { read::<u8>(addr + i) })) == 0
+}
+
+/// The RSDP lives on a 16 byte boundary in either the first
+/// KiB of the EBDA or the BIOS area below 1 MiB
+fn find_rsdp() -> Result<PhysAddr, Error> {
+    if memory::phys_to_virt(PhysAddr::new(0)).is_none() {
+        return Err(Error::MemoryNotInstalled);
+    }
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let ebda = (ebda..ebda + 1024).step_by(16);
+    let bios = (0xe_0000..0x10_0000).step_by(16);
+    ebda.chain(bios)
+        .map(PhysAddr::new)
+        .find(|&addr| unsafe { read::<[u8; 8]>(addr) } == *b"RSD PTR " && checksum(addr, 20))
+        .ok_or(Error::NoRsdp)
+}
+
+/// Find the table with `signature` through the XSDT
+/// if there is one, the RSDT if not
+fn find_table(rsdp: PhysAddr, signature: &'static [u8; 4]) -> Result<PhysAddr, Error> {
+    let revision = unsafe { read::<u8>(rsdp + 15u64) };
+    let (root, entry_size) = if revision >= 2 {
+        (unsafe { read::<u64>(rsdp + 24u64) }, 8)
+    } else {
+        (u64::from(unsafe { read::<u32>(rsdp + 16u64) }), 4)
+    };
+    let root = PhysAddr::new(root);
+    let root_len = u64::from(unsafe { read::<u32>(root + 4u64) });
+    if !checksum(root, root_len) {
+        return Err(Error::BadAcpiChecksum("RSDT"));
+    }
+    let entries = (root_len - SDT_HEADER_LEN) / entry_size;
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_LEN + i * entry_size;
+        let table = if entry_size == 8 {
+            unsafe { read::<u64>(entry) }
+        } else {
+            u64::from(unsafe { read::<u32>(entry) })
+        };
+        let table = PhysAddr::new(table);
+        if unsafe { read::<[u8; 4]>(table) } != *signature {
+            continue;
+        }
+        let len = u64::from(unsafe { read::<u32>(table + 4u64) });
+        if !checksum(table, len) {
+            return Err(Error::BadAcpiChecksum(core::str::from_utf8(signature).unwrap_or("?")));
+        }
+        return Ok(table);
+    }
+    Err(Error::NoAcpiTable(core::str::from_utf8(signature).unwrap_or("?")))
+}
@@ -2,43 +2,274 @@ use alloc::alloc::{
     GlobalAlloc,
     Layout,
 };
-use crate::error::Error;
-use spin::{Mutex, MutexGuard};
+use crate::{
+    error::Error,
+    memory::{self, BootInfoFrameAllocator},
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+pub mod buddy;
+pub mod bump;
+pub mod linked_list;
 pub mod slab;
+mod stats;
+use buddy::Buddy;
+use bump::Bumper;
+use linked_list::LinkedList;
 use slab::Slabber;
+pub use stats::{assert_no_leaks, BackendStats, ClassStats, Stats};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The size of the heap mapped by `init_heap`
 pub const HEAP_SIZE: usize = 100 * 1024;
+/// The default for how large the heap is allowed
+/// to grow, see `set_heap_limit`
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// The smallest amount the heap grows by at once
+pub const HEAP_GROWTH: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// The end of the mapped heap
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 pub trait Alloc {
     fn alloc(&mut self, layout: Layout) -> *mut u8;
     fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout);
+    /// The backend's view of the heap, for `allocator::stats`
+    fn stats(&self) -> BackendStats {
+        BackendStats::default()
+    }
 }
 
-pub struct Locked<A>(Mutex<A>);
+/// An allocator behind a lock, the lock disables interrupts
+/// so an interrupt handler can allocate
+pub struct Locked<A> {
+    inner: IrqSpinlock<A>,
+    counts: stats::Counts,
+}
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
-        Self(Mutex::new(inner))
+        Self {
+            inner: IrqSpinlock::new(inner),
+            counts: stats::Counts::new(),
+        }
+    }
+    pub fn lock(&self) -> IrqSpinlockGuard<A> {
+        self.inner.lock()
+    }
+}
+
+impl<A: Alloc> Locked<A> {
+    pub fn stats(&self) -> Stats {
+        let me = self.lock();
+        let mut stats = Stats {
+            heap_size: heap_size(),
+            backend: me.stats(),
+            ..Stats::default()
+        };
+        self.counts.fill(&mut stats);
+        stats
+    }
+}
+
+/// The allocators the kernel heap can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Bump,
+    Slab,
+    LinkedList,
+    Buddy,
+}
+
+impl Default for Backend {
+    /// Picked with the `alloc-*` cargo features,
+    /// `Slab` if none of them are enabled
+    fn default() -> Self {
+        if cfg!(feature = "alloc-bump") {
+            Self::Bump
+        } else if cfg!(feature = "alloc-linked-list") {
+            Self::LinkedList
+        } else if cfg!(feature = "alloc-buddy") {
+            Self::Buddy
+        } else {
+            Self::Slab
+        }
     }
-    pub fn lock(&self) -> MutexGuard<A> {
-        self.0.lock()
+}
+
+/// Whichever backend `init_heap_with` picked
+pub enum Dynamic {
+    Uninit,
+    Bump(Bumper),
+    Slab(Slabber),
+    LinkedList(LinkedList),
+    Buddy(Buddy),
+}
+
+impl Dynamic {
+    pub const fn new() -> Self {
+        Self::Uninit
+    }
+    pub unsafe fn init(&mut self, backend: Backend, heap_start: usize, heap_size: usize) {
+        *self = match backend {
+            Backend::Bump => Self::Bump(Bumper::new()),
+            Backend::Slab => Self::Slab(Slabber::new()),
+            Backend::LinkedList => Self::LinkedList(LinkedList::new()),
+            Backend::Buddy => Self::Buddy(Buddy::new()),
+        };
+        match self {
+            Self::Uninit => (),
+            Self::Bump(inner) => inner.init(heap_start, heap_size),
+            Self::Slab(inner) => inner.init(heap_start, heap_size),
+            Self::LinkedList(inner) => inner.init(heap_start, heap_size),
+            Self::Buddy(inner) => inner.init(heap_start, heap_size),
+        }
+    }
+    pub fn backend(&self) -> Option<Backend> {
+        match self {
+            Self::Uninit => None,
+            Self::Bump(_) => Some(Backend::Bump),
+            Self::Slab(_) => Some(Backend::Slab),
+            Self::LinkedList(_) => Some(Backend::LinkedList),
+            Self::Buddy(_) => Some(Backend::Buddy),
+        }
+    }
+}
+
+impl Alloc for Dynamic {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self {
+            Self::Uninit => core::ptr::null_mut(),
+            Self::Bump(inner) => inner.alloc(layout),
+            Self::Slab(inner) => inner.alloc(layout),
+            Self::LinkedList(inner) => inner.alloc(layout),
+            Self::Buddy(inner) => inner.alloc(layout),
+        }
+    }
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match self {
+            Self::Uninit => (),
+            Self::Bump(inner) => inner.dealloc(ptr, layout),
+            Self::Slab(inner) => inner.dealloc(ptr, layout),
+            Self::LinkedList(inner) => inner.dealloc(ptr, layout),
+            Self::Buddy(inner) => inner.dealloc(ptr, layout),
+        }
+    }
+    fn stats(&self) -> BackendStats {
+        match self {
+            Self::Uninit => BackendStats::default(),
+            Self::Bump(inner) => inner.stats(),
+            Self::Slab(inner) => inner.stats(),
+            Self::LinkedList(inner) => inner.stats(),
+            Self::Buddy(inner) => inner.stats(),
+        }
     }
 }
 
 #[global_allocator]
-static ALLOCATOR: Locked<Slabber> = Locked::new(Slabber::new());
-pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_alloc: &mut impl FrameAllocator<Size4KiB>) -> Result<(), Error> {
+static ALLOCATOR: Locked<Dynamic> = Locked::new(Dynamic::new());
+
+/// Map the initial heap with the default `Backend`
+/// and hand the page table and frame allocator over
+/// to `memory` so the heap can grow later on
+pub fn init_heap(mapper: OffsetPageTable<'static>, frame_alloc: BootInfoFrameAllocator) -> Result<(), Error> {
+    init_heap_with(Backend::default(), mapper, frame_alloc)
+}
+
+/// Like `init_heap` but with a specific `Backend`
+pub fn init_heap_with(backend: Backend, mut mapper: OffsetPageTable<'static>, mut frame_alloc: BootInfoFrameAllocator) -> Result<(), Error> {
+    map_heap(HEAP_START, HEAP_SIZE, &mut mapper, &mut frame_alloc)?;
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
+    unsafe {
+        ALLOCATOR.lock().init(backend, HEAP_START, HEAP_SIZE);
+    }
+    memory::install(mapper, frame_alloc);
+    Ok(())
+}
+
+/// The backend the heap was initialized with
+pub fn backend() -> Option<Backend> {
+    ALLOCATOR.lock().backend()
+}
+
+/// A snapshot of the global allocator's bookkeeping
+pub fn stats() -> Stats {
+    ALLOCATOR.stats()
+}
+
+/// Set the most the heap is allowed to grow to, this
+/// can't shrink the heap below its current size
+pub fn set_heap_limit(size: usize) {
+    HEAP_LIMIT.store(size, Ordering::SeqCst);
+}
+
+/// The number of bytes currently mapped for the heap
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
+
+/// Map at least `min` more bytes onto the end of the
+/// heap, returning the number of bytes that were actually
+/// mapped. This is called with the allocator locked
+/// so it can't allocate anything itself
+fn grow_heap(min: usize) -> Option<usize> {
+    let end = HEAP_END.load(Ordering::SeqCst);
+    let limit = HEAP_START + HEAP_LIMIT.load(Ordering::SeqCst);
+    let size = align(min.max(HEAP_GROWTH), PAGE_SIZE).min(limit.saturating_sub(end));
+    if size < min {
+        return None;
+    }
+    let mapped = memory::try_with_kernel(|k| {
+        let mut mapped = 0;
+        while mapped < size {
+            if map_heap(end + mapped, PAGE_SIZE, &mut k.mapper, &mut k.frames).is_err() {
+                break;
+            }
+            mapped += PAGE_SIZE;
+        }
+        mapped
+    })?;
+    HEAP_END.store(end + mapped, Ordering::SeqCst);
+    if mapped == 0 {
+        None
+    } else {
+        Some(mapped)
+    }
+}
+
+/// First fit allocation from `heap`, growing
+/// it if there isn't room
+fn alloc_growing(heap: &mut linked_list_allocator::Heap, layout: Layout) -> *mut u8 {
+    if let Ok(p) = heap.allocate_first_fit(layout) {
+        return p.as_ptr();
+    }
+    // out of room, map some more pages onto the
+    // end of the heap and try again
+    let needed = layout.size() + layout.align();
+    if let Some(added) = grow_heap(needed) {
+        unsafe {
+            heap.extend(added);
+        }
+        if let Ok(p) = heap.allocate_first_fit(layout) {
+            return p.as_ptr();
+        }
+    }
+    core::ptr::null_mut()
+}
+
+fn map_heap(start: usize, size: usize, mapper: &mut impl Mapper<Size4KiB>, frame_alloc: &mut impl FrameAllocator<Size4KiB>) -> Result<(), Error> {
     let pages = {
-        let start = VirtAddr::new(HEAP_START as u64);
-        let end = start + HEAP_SIZE - 1u64;
+        let start = VirtAddr::new(start as u64);
+        let end = start + size - 1u64;
         let start = Page::containing_address(start);
         let end = Page::containing_address(end);
         Page::range_inclusive(start, end)
@@ -50,21 +281,24 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_alloc: &mut impl Fram
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         mapper.map_to(page, frame, flags, frame_alloc)?.flush();
     }
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
     Ok(())
 }
 
 unsafe impl<I: Alloc> GlobalAlloc for Locked<I> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut me = self.lock();
-        me.alloc(layout)
-
+        let ptr = me.alloc(layout);
+        if ptr.is_null() {
+            self.counts.failed();
+        } else {
+            self.counts.alloc(layout.size());
+        }
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut me = self.lock();
         me.dealloc(ptr, layout);
+        self.counts.dealloc(layout.size());
     }
 }
 
@@ -0,0 +1,536 @@
+use super::{align, Alloc, BackendStats};
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+
+/// The smallest block is 16 bytes, enough room
+/// for the free list links
+const MIN_ORDER: usize = 4;
+const MAX_ORDER: usize = 32;
+const ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
+/// The most regions the heap can be made of, memory the
+/// heap grows by extends the zone it follows on from so
+/// this is only used up by memory that isn't contiguous
+const MAX_ZONES: usize = 32;
+const PAGE_SIZE: usize = 4096;
+
+/// The links stored in every free block
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A contiguous region the allocator hands blocks out of.
+///
+/// Every block in a zone is aligned to its own size, so the
+/// buddy of the order `k` block at `addr` is at `addr ^ (1 << k)`.
+/// Whether a block is free is tracked in a bitmap at the
+/// end of the zone, one bit per possible block per order,
+/// which is what keeps coalescing O(log n). Keeping the bitmap
+/// at the end means a zone can grow by moving it further up
+#[derive(Clone, Copy)]
+struct Zone {
+    /// The first address handed out, the bitmap indexes from here
+    start: usize,
+    /// The end of the blocks, the bitmap is between here and `limit`
+    end: usize,
+    /// The end of the zone's memory
+    limit: usize,
+    max_order: usize,
+    bitmap: *mut u8,
+    /// The first bit of each order in `bitmap`
+    offsets: [usize; ORDERS],
+}
+
+impl Zone {
+    /// Lay out a zone over `start..limit` without touching
+    /// the memory, `None` if there's no room for a block
+    fn layout(start: usize, limit: usize) -> Option<Self> {
+        let start = align(start, 1 << MIN_ORDER);
+        if limit <= start {
+            return None;
+        }
+        let max_order = floor_log2(limit - start).min(MAX_ORDER);
+        if max_order < MIN_ORDER {
+            return None;
+        }
+        let mut offsets = [0; ORDERS];
+        let mut bits = 0;
+        for order in MIN_ORDER..=max_order {
+            offsets[order - MIN_ORDER] = bits;
+            bits += (limit - start + (1 << order) - 1) >> order;
+        }
+        let bitmap_len = (bits + 7) / 8;
+        let end = limit.checked_sub(bitmap_len)? & !((1 << MIN_ORDER) - 1);
+        if end <= start {
+            return None;
+        }
+        Some(Self {
+            start,
+            end,
+            limit,
+            max_order,
+            bitmap: end as *mut u8,
+            offsets,
+        })
+    }
+
+    /// Lay out a zone over `start..start + size`, the bitmap
+    /// is zeroed but no blocks are free yet
+    unsafe fn new(start: usize, size: usize) -> Option<Self> {
+        let zone = Self::layout(start, start + size)?;
+        zone.clear_bitmap();
+        Some(zone)
+    }
+
+    unsafe fn clear_bitmap(&self) {
+        core::ptr::write_bytes(self.bitmap, 0, self.limit - self.end);
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn bit(&self, addr: usize, order: usize) -> (usize, u8) {
+        let idx = self.offsets[order - MIN_ORDER] + ((addr - self.start) >> order);
+        (idx / 8, 1 << (idx % 8))
+    }
+
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        let (byte, mask) = self.bit(addr, order);
+        unsafe { *self.bitmap.add(byte) & mask != 0 }
+    }
+
+    fn set_free(&self, addr: usize, order: usize, free: bool) {
+        let (byte, mask) = self.bit(addr, order);
+        unsafe {
+            let b = self.bitmap.add(byte);
+            if free {
+                *b |= mask;
+            } else {
+                *b &= !mask;
+            }
+        }
+    }
+
+    /// The buddy of the order `order` block at `addr`,
+    /// if it is inside this zone
+    fn buddy(&self, addr: usize, order: usize) -> Option<usize> {
+        if order >= self.max_order {
+            return None;
+        }
+        let parent = addr & !((1 << (order + 1)) - 1);
+        if parent < self.start || parent + (1 << (order + 1)) > self.end {
+            return None;
+        }
+        Some(addr ^ (1 << order))
+    }
+}
+
+/// A power of two buddy allocator, blocks are split
+/// and coalesced in O(log n)
+pub struct Buddy {
+    free: [Option<NonNull<FreeBlock>>; ORDERS],
+    zones: [Option<Zone>; MAX_ZONES],
+    /// The end of the memory handed to us, `grow` only
+    /// adds more if that is also the end of the heap
+    heap_end: usize,
+    stats: BackendStats,
+}
+
+// Blocks are only ever reached through the `Buddy`
+// that owns them
+unsafe impl Send for Buddy {}
+
+impl Buddy {
+    pub const fn new() -> Self {
+        Self {
+            free: [None; ORDERS],
+            zones: [None; MAX_ZONES],
+            heap_end: 0,
+            stats: BackendStats::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_zone(heap_start, heap_size);
+    }
+
+    /// Start handing out blocks from `start..start + size`
+    unsafe fn add_zone(&mut self, start: usize, size: usize) -> bool {
+        let slot = match self.zones.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let zone = match Zone::new(start, size) {
+            Some(zone) => zone,
+            None => return false,
+        };
+        self.zones[slot] = Some(zone);
+        self.heap_end = self.heap_end.max(zone.limit);
+        self.free_range(&zone, zone.start, zone.end);
+        true
+    }
+
+    /// Grow the zone in `slot` by the `size` bytes right after it,
+    /// the bitmap moves to the new end and the old one is freed
+    unsafe fn extend(&mut self, slot: usize, size: usize) -> bool {
+        let old = match self.zones[slot] {
+            Some(zone) => zone,
+            None => return false,
+        };
+        // the new bitmap can't reach down into blocks
+        // that are in use, only over the old bitmap
+        let zone = match Zone::layout(old.start, old.limit + size) {
+            Some(zone) if zone.end >= old.end => zone,
+            _ => return false,
+        };
+        zone.clear_bitmap();
+        for order in MIN_ORDER..=old.max_order {
+            let mut block = self.free[order - MIN_ORDER];
+            while let Some(b) = block {
+                let addr = b.as_ptr() as usize;
+                if old.contains(addr) {
+                    zone.set_free(addr, order, true);
+                }
+                block = b.as_ref().next;
+            }
+        }
+        self.zones[slot] = Some(zone);
+        self.heap_end = self.heap_end.max(zone.limit);
+        self.free_range(&zone, old.end, zone.end);
+        true
+    }
+
+    /// Free `start..end` of `zone` as the largest aligned
+    /// blocks that fit, merging them with free neighbours
+    fn free_range(&mut self, zone: &Zone, start: usize, end: usize) {
+        let mut addr = start;
+        while addr + (1 << MIN_ORDER) <= end {
+            let mut order = (addr.trailing_zeros() as usize).min(zone.max_order);
+            while addr + (1 << order) > end {
+                order -= 1;
+            }
+            self.release(zone, addr, order);
+            addr += 1 << order;
+        }
+    }
+
+    fn zone(&self, addr: usize) -> Zone {
+        self.zones
+            .iter()
+            .flatten()
+            .find(|z| z.contains(addr))
+            .copied()
+            .expect("address outside of buddy allocator")
+    }
+
+    fn push(&mut self, zone: &Zone, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let next = self.free[order - MIN_ORDER];
+        unsafe {
+            block.write(FreeBlock { prev: None, next });
+            if let Some(mut next) = next {
+                next.as_mut().prev = NonNull::new(block);
+            }
+        }
+        self.free[order - MIN_ORDER] = NonNull::new(block);
+        zone.set_free(addr, order, true);
+    }
+
+    fn remove(&mut self, zone: &Zone, addr: usize, order: usize) {
+        let block = unsafe { &mut *(addr as *mut FreeBlock) };
+        match block.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = block.next },
+            None => self.free[order - MIN_ORDER] = block.next,
+        }
+        if let Some(mut next) = block.next {
+            unsafe {
+                next.as_mut().prev = block.prev;
+            }
+        }
+        zone.set_free(addr, order, false);
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let addr = self.free[order - MIN_ORDER]?.as_ptr() as usize;
+        let zone = self.zone(addr);
+        self.remove(&zone, addr, order);
+        Some(addr)
+    }
+
+    fn alloc_order(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free[o - MIN_ORDER].is_some())?;
+        let addr = self.pop(found)?;
+        let zone = self.zone(addr);
+        // hand the upper halves back until the block is the right size
+        for o in (order..found).rev() {
+            self.push(&zone, addr + (1 << o), o);
+        }
+        Some(addr)
+    }
+
+    /// Put the order `order` block at `addr` on the free
+    /// list, coalescing it with its buddies first
+    fn release(&mut self, zone: &Zone, mut addr: usize, mut order: usize) {
+        while let Some(buddy) = zone.buddy(addr, order) {
+            if !zone.is_free(buddy, order) {
+                break;
+            }
+            self.remove(zone, buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(zone, addr, order);
+    }
+
+    /// Map enough onto the heap for an order `order` block,
+    /// extending the zone at the end of the heap if there is one.
+    /// A `Buddy` that doesn't end where the heap does isn't the
+    /// heap's, so it has nothing to grow into
+    fn grow(&mut self, order: usize) -> bool {
+        // an aligned block fits in twice its size, and
+        // the bitmap takes under 1/32 of whatever is added
+        let need = 2 << order;
+        let wanted = need + need / 32 + PAGE_SIZE;
+        let start = self.heap_end;
+        if start != super::HEAP_START + super::heap_size() {
+            return false;
+        }
+        let last = self
+            .zones
+            .iter()
+            .position(|z| matches!(z, Some(z) if z.limit == start));
+        // check before mapping anything, so the
+        // pages aren't left mapped with no zone
+        if last.is_none() && self.zones.iter().all(Option::is_some) {
+            return false;
+        }
+        let added = match super::grow_heap(wanted) {
+            Some(added) => added,
+            None => return false,
+        };
+        // the heap has grown whether or not a zone takes it
+        self.heap_end = start + added;
+        unsafe {
+            match last {
+                Some(slot) => self.extend(slot, added),
+                None => self.add_zone(start, added),
+            }
+        }
+    }
+}
+
+impl Alloc for Buddy {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let order = match order(&layout) {
+            Some(order) => order,
+            None => return core::ptr::null_mut(),
+        };
+        let addr = match self.alloc_order(order) {
+            Some(addr) => addr,
+            None if self.grow(order) => match self.alloc_order(order) {
+                Some(addr) => addr,
+                None => return core::ptr::null_mut(),
+            },
+            None => return core::ptr::null_mut(),
+        };
+        self.stats.fallback_alloc(1 << order);
+        addr as *mut u8
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let order = order(&layout).expect("invalid layout passed to dealloc");
+        self.stats.fallback_dealloc(1 << order);
+        let zone = self.zone(ptr as usize);
+        self.release(&zone, ptr as usize, order);
+    }
+
+    fn stats(&self) -> BackendStats {
+        self.stats
+    }
+}
+
+/// The order of the smallest block that fits `layout`
+fn order(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(1 << MIN_ORDER)
+        .checked_next_power_of_two()?;
+    let order = size.trailing_zeros() as usize;
+    if order > MAX_ORDER {
+        None
+    } else {
+        Some(order)
+    }
+}
+
+fn floor_log2(n: usize) -> usize {
+    (core::mem::size_of::<usize>() * 8 - 1) - n.leading_zeros() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    const ARENA_SIZE: usize = 256 * 1024;
+    #[repr(align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    fn buddy() -> Buddy {
+        let mut b = Buddy::new();
+        unsafe {
+            b.init(ARENA.0.as_mut_ptr() as usize, ARENA_SIZE);
+        }
+        b
+    }
+
+    /// A tiny xorshift so the tests are repeatable
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    /// The size of the biggest block the arena can hold
+    fn largest(b: &Buddy) -> usize {
+        (MIN_ORDER..=MAX_ORDER)
+            .rev()
+            .find(|&o| b.free[o - MIN_ORDER].is_some())
+            .map(|o| 1 << o)
+            .unwrap()
+    }
+
+    #[kern_test]
+    fn test_buddy_alignment() {
+        let mut b = buddy();
+        let mut align = 1;
+        while align <= 4096 {
+            for &size in &[1, 7, 16, 100, 1000] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = b.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                b.dealloc(ptr, layout);
+            }
+            align *= 2;
+        }
+    }
+
+    #[kern_test]
+    fn test_buddy_mixed() {
+        const LIVE: usize = 32;
+        let mut b = buddy();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut live: [Option<(*mut u8, Layout, u8)>; LIVE] = [None; LIVE];
+        for i in 0..5_000 {
+            let slot = rng.next() % LIVE;
+            if let Some((ptr, layout, fill)) = live[slot].take() {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                assert!(bytes.iter().all(|&b| b == fill), "block was overwritten");
+                b.dealloc(ptr, layout);
+            } else {
+                let size = 1 + rng.next() % 2000;
+                let align = 1 << (rng.next() % 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = b.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                let fill = i as u8;
+                unsafe {
+                    core::ptr::write_bytes(ptr, fill, size);
+                }
+                live[slot] = Some((ptr, layout, fill));
+            }
+        }
+        for (ptr, layout, _) in live.iter().flatten() {
+            b.dealloc(*ptr, *layout);
+        }
+    }
+
+    /// The bytes on the free lists
+    fn free_bytes(b: &Buddy) -> usize {
+        let mut total = 0;
+        for order in MIN_ORDER..=MAX_ORDER {
+            let mut block = b.free[order - MIN_ORDER];
+            while let Some(free) = block {
+                total += 1 << order;
+                block = unsafe { free.as_ref().next };
+            }
+        }
+        total
+    }
+
+    /// Take every smallest block there is without growing, the
+    /// blocks are chained together through their first word
+    fn take_all(b: &mut Buddy) -> (*mut usize, usize) {
+        let mut head: *mut usize = core::ptr::null_mut();
+        let mut count = 0;
+        while let Some(addr) = b.alloc_order(MIN_ORDER) {
+            let ptr = addr as *mut usize;
+            unsafe {
+                ptr.write(head as usize);
+            }
+            head = ptr;
+            count += 1;
+        }
+        (head, count)
+    }
+
+    fn free_all(b: &mut Buddy, mut head: *mut usize) {
+        let layout = Layout::from_size_align(1 << MIN_ORDER, 8).unwrap();
+        while !head.is_null() {
+            let next = unsafe { head.read() } as *mut usize;
+            b.dealloc(head as *mut u8, layout);
+            head = next;
+        }
+    }
+
+    #[kern_test]
+    fn test_buddy_coalesces() {
+        let mut b = buddy();
+        let before = largest(&b);
+        let total = free_bytes(&b);
+        let (head, count) = take_all(&mut b);
+        assert_eq!(count << MIN_ORDER, total);
+        assert!(b.free.iter().all(Option::is_none));
+        free_all(&mut b, head);
+        assert_eq!(largest(&b), before);
+        let big = Layout::from_size_align(before, 8).unwrap();
+        let ptr = b.alloc(big);
+        assert!(!ptr.is_null());
+        b.dealloc(ptr, big);
+    }
+
+    #[kern_test]
+    fn test_buddy_extends_zone() {
+        let half = ARENA_SIZE / 2;
+        let mut b = Buddy::new();
+        unsafe {
+            b.init(ARENA.0.as_mut_ptr() as usize, half);
+        }
+        // some blocks are live while the zone grows
+        let (head, _) = take_all(&mut b);
+        let before = free_bytes(&b);
+        assert!(unsafe { b.extend(0, half) });
+        assert!(b.zones[1..].iter().all(Option::is_none));
+        // the old bitmap is freed along with the new memory
+        let added = free_bytes(&b) - before;
+        assert!(added > half - half / 32);
+        free_all(&mut b, head);
+        let total = free_bytes(&b);
+        let largest_before = largest(&b);
+        assert!(largest_before >= half / 2);
+        let (head, count) = take_all(&mut b);
+        assert_eq!(count << MIN_ORDER, total);
+        free_all(&mut b, head);
+        assert_eq!(largest(&b), largest_before);
+    }
+}
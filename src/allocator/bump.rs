@@ -30,6 +30,12 @@ impl Alloc for Bumper {
     fn alloc(&mut self, layout: Layout) -> *mut u8 {
         let start = align(self.next, layout.align());
         let end = start + layout.size();
+        if end > self.heap_end {
+            match super::grow_heap(end - self.heap_end) {
+                Some(added) => self.heap_end += added,
+                None => return core::ptr::null_mut(),
+            }
+        }
         if end > self.heap_end {
             core::ptr::null_mut()
         } else {
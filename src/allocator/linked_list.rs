@@ -0,0 +1,41 @@
+use super::{Alloc, BackendStats};
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+
+/// Nothing but `linked_list_allocator`'s first fit heap
+pub struct LinkedList {
+    heap: linked_list_allocator::Heap,
+    stats: BackendStats,
+}
+
+impl LinkedList {
+    pub const fn new() -> Self {
+        Self {
+            heap: linked_list_allocator::Heap::empty(),
+            stats: BackendStats::new(),
+        }
+    }
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+}
+
+impl Alloc for LinkedList {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = super::alloc_growing(&mut self.heap, layout);
+        if !ptr.is_null() {
+            self.stats.fallback_alloc(layout.size());
+        }
+        ptr
+    }
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        unsafe {
+            self.heap.deallocate(ptr, layout);
+        }
+        self.stats.fallback_dealloc(layout.size());
+    }
+    fn stats(&self) -> BackendStats {
+        self.stats
+    }
+}
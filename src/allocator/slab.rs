@@ -1,73 +1,273 @@
-
-use super::Alloc;
+use super::{align, Alloc, BackendStats};
 use alloc::alloc::Layout;
+use core::ptr::NonNull;
+
+#[cfg(feature = "heap-debug")]
+mod debug;
+
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// The number of slab size classes
+pub const CLASS_COUNT: usize = BLOCK_SIZES.len();
+/// The size of the chunk of memory a size class takes
+/// from the fallback heap at once, slabs are aligned to
+/// this so the slab a block belongs to is always
+/// `block & !(SLAB_SIZE - 1)`
+const SLAB_SIZE: usize = 8 * 1024;
 
 pub struct Cut {
     next: Option<&'static mut Cut>,
 }
+
+/// The header at the start of every slab, the rest
+/// of the slab is carved into blocks of one size.
+///
+/// Blocks are carved lazily, `fresh` marks where
+/// the never used blocks start so taking a new slab is
+/// O(1) no matter how many blocks fit in it
+struct Slab {
+    #[cfg(feature = "heap-debug")]
+    magic: usize,
+    class: usize,
+    /// The number of blocks handed out
+    in_use: usize,
+    /// Blocks that have been handed back
+    free: Option<&'static mut Cut>,
+    /// The offset of the first block that was never handed out
+    fresh: usize,
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+}
+
+impl Slab {
+    fn first_block(class: usize) -> usize {
+        align(core::mem::size_of::<Slab>(), BLOCK_SIZES[class])
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZES[self.class]
+    }
+
+    fn is_full(&self) -> bool {
+        self.free.is_none() && self.fresh + self.block_size() > SLAB_SIZE
+    }
+
+    fn take_block(&mut self) -> *mut u8 {
+        self.in_use += 1;
+        if let Some(cut) = self.free.take() {
+            self.free = cut.next.take();
+            return cut as *mut Cut as *mut u8;
+        }
+        let block = self as *mut Slab as usize + self.fresh;
+        self.fresh += self.block_size();
+        block as *mut u8
+    }
+
+    fn give_block(&mut self, ptr: *mut u8) {
+        let cut = Cut {
+            next: self.free.take(),
+        };
+        let cut_ptr = ptr as *mut Cut;
+        unsafe {
+            cut_ptr.write(cut);
+            self.free = Some(&mut *cut_ptr);
+        }
+        self.in_use -= 1;
+    }
+}
+
 pub struct Slabber {
-    slabs: [
-        Option<&'static mut Cut>; BLOCK_SIZES.len()
-    ],
+    /// For each size class, the slabs that have at
+    /// least one block available
+    slabs: [Option<NonNull<Slab>>; CLASS_COUNT],
+    /// For each size class, an empty slab kept rather than
+    /// handed back so a class that keeps taking and freeing
+    /// one block doesn't carve a new slab every time
+    empty: [Option<NonNull<Slab>>; CLASS_COUNT],
     fallback: linked_list_allocator::Heap,
+    stats: BackendStats,
 }
 
+// Slabs are only ever reached through the `Slabber`
+// that owns them
+unsafe impl Send for Slabber {}
+
 impl Slabber {
     pub const fn new() -> Self {
         Self {
-            slabs: [None; BLOCK_SIZES.len()],
+            slabs: [None; CLASS_COUNT],
+            empty: [None; CLASS_COUNT],
             fallback: linked_list_allocator::Heap::empty(),
+            stats: BackendStats::new(),
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback.init(heap_start, heap_size);
+        for (class, size) in self.stats.classes.iter_mut().zip(BLOCK_SIZES) {
+            class.block_size = *size;
+        }
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback.allocate_first_fit(layout) {
-            Ok(p) => p.as_ptr(),
-            Err(_) => core::ptr::null_mut(),
+        let ptr = super::alloc_growing(&mut self.fallback, layout);
+        if !ptr.is_null() {
+            self.stats.fallback_alloc(layout.size());
         }
+        ptr
     }
-}
 
-impl Alloc for Slabber {
-    fn alloc(&mut self, mut layout: Layout) -> *mut u8 {
-        if let Some(idx) = slab_index(&layout) {
-            if let Some(cut) = self.slabs[idx].take() {
-                self.slabs[idx] = cut.next.take();
-                return cut as *mut Cut as *mut u8;
-            } else {
-                let size = BLOCK_SIZES[idx];
-                let align = size;
-                layout = Layout::from_size_align(size, align).unwrap();
+    fn fallback_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        unsafe {
+            self.fallback.deallocate(ptr, layout);
+        }
+        self.stats.fallback_dealloc(layout.size());
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    fn slab_alloc(&mut self, class: usize, _layout: Layout) -> *mut u8 {
+        let mut slab = match self.slabs[class] {
+            Some(slab) => slab,
+            None => match self.new_slab(class) {
+                Some(slab) => slab,
+                None => return core::ptr::null_mut(),
+            },
+        };
+        if self.empty[class] == Some(slab) {
+            self.empty[class] = None;
+        }
+        let slab = unsafe { slab.as_mut() };
+        #[cfg(feature = "heap-debug")]
+        let reused = slab.free.is_some();
+        let block = slab.take_block();
+        #[cfg(feature = "heap-debug")]
+        debug::on_alloc(block, class, &_layout, reused);
+        self.stats.classes[class].allocs += 1;
+        if slab.is_full() {
+            self.unlink(slab);
+        }
+        block
+    }
+
+    fn slab_dealloc(&mut self, ptr: *mut u8) {
+        let slab = unsafe { &mut *((ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab) };
+        let was_full = slab.is_full();
+        slab.give_block(ptr);
+        self.stats.classes[slab.class].frees += 1;
+        if slab.in_use == 0 && self.empty[slab.class].is_some() {
+            if !was_full {
+                self.unlink(slab);
+            }
+            self.release_slab(slab);
+        } else {
+            if slab.in_use == 0 {
+                self.empty[slab.class] = Some(NonNull::from(&mut *slab));
+            }
+            if was_full {
+                self.push(slab);
             }
         }
-        self.fallback_alloc(layout)
     }
-    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        if let Some(idx) = slab_index(&layout) {
-            let cut = Cut {
-                next: self.slabs[idx].take()
-            };
-            assert!(core::mem::size_of::<Cut>() <= BLOCK_SIZES[idx]);
-            assert!(core::mem::align_of::<Cut>() <= BLOCK_SIZES[idx]);
-            let cut_ptr = ptr as *mut Cut;
+
+    /// Give an empty slab, already unlinked, back to the fallback heap
+    fn release_slab(&mut self, slab: &mut Slab) {
+        #[cfg(feature = "heap-debug")]
+        {
+            slab.magic = 0;
+        }
+        self.stats.classes[slab.class].slabs -= 1;
+        self.fallback_dealloc(slab as *mut Slab as *mut u8, Self::slab_layout());
+    }
+
+    /// Take a fresh slab from the fallback heap and put it at
+    /// the front of `class`'s list
+    fn new_slab(&mut self, class: usize) -> Option<NonNull<Slab>> {
+        let ptr = self.fallback_alloc(Self::slab_layout()) as *mut Slab;
+        let slab = NonNull::new(ptr)?;
+        unsafe {
+            ptr.write(Slab {
+                #[cfg(feature = "heap-debug")]
+                magic: debug::SLAB_MAGIC,
+                class,
+                in_use: 0,
+                free: None,
+                fresh: Slab::first_block(class),
+                prev: None,
+                next: None,
+            });
+            self.push(&mut *ptr);
+        }
+        self.stats.classes[class].slabs += 1;
+        Some(slab)
+    }
+
+    fn push(&mut self, slab: &mut Slab) {
+        slab.prev = None;
+        slab.next = self.slabs[slab.class];
+        if let Some(mut next) = slab.next {
             unsafe {
-                cut_ptr.write(cut);
-                self.slabs[idx] = Some(&mut *cut_ptr);
+                next.as_mut().prev = Some(NonNull::from(&mut *slab));
             }
-        } else {
-            let ptr = core::ptr::NonNull::new(ptr).unwrap();
+        }
+        self.slabs[slab.class] = Some(NonNull::from(slab));
+    }
+
+    fn unlink(&mut self, slab: &mut Slab) {
+        match slab.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = slab.next },
+            None => self.slabs[slab.class] = slab.next,
+        }
+        if let Some(mut next) = slab.next {
             unsafe {
-                self.fallback.deallocate(ptr, layout);
+                next.as_mut().prev = slab.prev;
             }
         }
+        slab.prev = None;
+        slab.next = None;
+    }
+}
+
+impl Alloc for Slabber {
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match slab_index(&layout) {
+            Some(idx) => self.slab_alloc(idx, layout),
+            #[cfg(not(feature = "heap-debug"))]
+            None => self.fallback_alloc(layout),
+            #[cfg(feature = "heap-debug")]
+            None => {
+                let ptr = self.fallback_alloc(debug::fallback_layout(&layout));
+                if !ptr.is_null() {
+                    debug::on_fallback_alloc(ptr, &layout);
+                }
+                ptr
+            }
+        }
+    }
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(_idx) = slab_index(&layout) {
+            #[cfg(feature = "heap-debug")]
+            debug::on_dealloc(ptr, _idx, &layout);
+            self.slab_dealloc(ptr);
+        } else {
+            #[cfg(feature = "heap-debug")]
+            let layout = {
+                debug::on_fallback_dealloc(ptr, &layout);
+                debug::fallback_layout(&layout)
+            };
+            self.fallback_dealloc(ptr, layout);
+        }
+    }
+    fn stats(&self) -> BackendStats {
+        self.stats
     }
 }
 
 fn slab_index(layout: &Layout) -> Option<usize> {
+    #[cfg(not(feature = "heap-debug"))]
     let size = layout.size().max(layout.align());
+    #[cfg(feature = "heap-debug")]
+    let size = (layout.size() + debug::OVERHEAD).max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= size)
-}
\ No newline at end of file
+}
@@ -0,0 +1,194 @@
+//! Heap corruption checks for the slab allocator, enabled
+//! with the `heap-debug` feature.
+//!
+//! Every block ends with a `Trailer` recording the layout it
+//! was allocated with and whether it is live, the bytes between
+//! the end of the allocation and the trailer are a red zone. Freed
+//! blocks are poisoned so writes after a free are caught when the
+//! block is handed out again.
+//!
+//! Allocations too big for a slab get the same red zone and
+//! trailer from the fallback heap, they are poisoned when freed
+//! but the fallback heap can't say when memory is reused so
+//! writes after a free aren't caught. The other backends
+//! aren't checked at all.
+use super::{Cut, Slab, BLOCK_SIZES, SLAB_SIZE};
+use crate::allocator::align;
+use alloc::alloc::Layout;
+use core::{
+    fmt,
+    mem::{align_of, size_of},
+};
+
+/// Written into every slab header, so a pointer that
+/// was never a slab block isn't taken for one
+pub const SLAB_MAGIC: usize = 0x51ab_51ab_51ab_51ab;
+const LIVE: u32 = 0xa110_ca7e;
+const FREED: u32 = 0xdead_b10c;
+const RED_ZONE: usize = 8;
+const RED_ZONE_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+#[repr(C)]
+struct Trailer {
+    size: u32,
+    align: u32,
+    state: u32,
+    _pad: u32,
+}
+
+/// The extra room every block needs on top of the
+/// requested size
+pub const OVERHEAD: usize = RED_ZONE + size_of::<Trailer>();
+
+fn trailer(block: *mut u8, class: usize) -> *mut Trailer {
+    (block as usize + BLOCK_SIZES[class] - size_of::<Trailer>()) as *mut Trailer
+}
+
+/// The bytes from `start` up to the trailer
+fn bytes_before_trailer(block: *mut u8, class: usize, start: usize) -> &'static mut [u8] {
+    let len = BLOCK_SIZES[class] - size_of::<Trailer>() - start;
+    unsafe { core::slice::from_raw_parts_mut(block.add(start), len) }
+}
+
+/// Check a block that is about to be handed out and set up
+/// its red zone and trailer, `reused` blocks come from the
+/// free list and should still be poisoned
+pub fn on_alloc(block: *mut u8, class: usize, layout: &Layout, reused: bool) {
+    if reused
+        && bytes_before_trailer(block, class, size_of::<Cut>())
+            .iter()
+            .any(|&b| b != POISON_BYTE)
+    {
+        panic!(
+            "heap corruption: {:#x} (size class {}) was written to after it was freed",
+            block as usize, BLOCK_SIZES[class]
+        );
+    }
+    for b in bytes_before_trailer(block, class, layout.size()) {
+        *b = RED_ZONE_BYTE;
+    }
+    unsafe {
+        trailer(block, class).write(Trailer {
+            size: layout.size() as u32,
+            align: layout.align() as u32,
+            state: LIVE,
+            _pad: 0,
+        });
+    }
+}
+
+/// Check a block that is being freed as `layout`,
+/// then mark it freed and poison it
+pub fn on_dealloc(block: *mut u8, class: usize, layout: &Layout) {
+    let addr = block as usize;
+    let slab = unsafe { &*((addr & !(SLAB_SIZE - 1)) as *const Slab) };
+    if slab.magic != SLAB_MAGIC {
+        panic!(
+            "heap corruption: {:#x} (size class {}) is not in a live slab, double free or bad pointer",
+            addr, BLOCK_SIZES[class]
+        );
+    }
+    if slab.class != class {
+        panic!(
+            "heap corruption: {:#x} was allocated from size class {} but freed as size class {}",
+            addr, BLOCK_SIZES[slab.class], BLOCK_SIZES[class]
+        );
+    }
+    let offset = addr - (slab as *const Slab as usize) - Slab::first_block(class);
+    if offset % BLOCK_SIZES[class] != 0 {
+        panic!(
+            "heap corruption: {:#x} (size class {}) is not the start of a block",
+            addr, BLOCK_SIZES[class]
+        );
+    }
+    let trailer = unsafe { &mut *trailer(block, class) };
+    check_live(
+        trailer,
+        addr,
+        layout,
+        bytes_before_trailer(block, class, layout.size()),
+        format_args!("size class {}", BLOCK_SIZES[class]),
+    );
+    for b in bytes_before_trailer(block, class, 0) {
+        *b = POISON_BYTE;
+    }
+}
+
+/// Where the trailer of a fallback allocation of `layout` goes
+fn fallback_trailer_offset(layout: &Layout) -> usize {
+    align(layout.size() + RED_ZONE, align_of::<Trailer>())
+}
+
+/// The layout to take from the fallback heap for an
+/// allocation of `layout`, with room for the red zone and trailer
+pub fn fallback_layout(layout: &Layout) -> Layout {
+    let size = fallback_trailer_offset(layout) + size_of::<Trailer>();
+    Layout::from_size_align(size, layout.align()).expect("allocation too large for heap-debug")
+}
+
+/// Set up the red zone and trailer of a fallback allocation
+pub fn on_fallback_alloc(ptr: *mut u8, layout: &Layout) {
+    let offset = fallback_trailer_offset(layout);
+    unsafe {
+        core::ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, offset - layout.size());
+        (ptr.add(offset) as *mut Trailer).write(Trailer {
+            size: layout.size() as u32,
+            align: layout.align() as u32,
+            state: LIVE,
+            _pad: 0,
+        });
+    }
+}
+
+/// Check a fallback allocation that is being freed
+/// as `layout`, then mark it freed and poison it
+pub fn on_fallback_dealloc(ptr: *mut u8, layout: &Layout) {
+    let offset = fallback_trailer_offset(layout);
+    let trailer = unsafe { &mut *(ptr.add(offset) as *mut Trailer) };
+    let red_zone =
+        unsafe { core::slice::from_raw_parts(ptr.add(layout.size()), offset - layout.size()) };
+    check_live(trailer, ptr as usize, layout, red_zone, "fallback");
+    unsafe {
+        core::ptr::write_bytes(ptr, POISON_BYTE, offset);
+    }
+}
+
+/// Check the trailer and red zone of the live allocation
+/// at `addr` that is being freed as `layout`, then mark it freed
+fn check_live(
+    trailer: &mut Trailer,
+    addr: usize,
+    layout: &Layout,
+    red_zone: &[u8],
+    kind: impl fmt::Display,
+) {
+    match trailer.state {
+        LIVE => (),
+        FREED => panic!("heap corruption: double free of {:#x} ({})", addr, kind),
+        _ => panic!(
+            "heap corruption: {:#x} ({}) was never allocated or its trailer was overwritten",
+            addr, kind
+        ),
+    }
+    if trailer.size as usize != layout.size() || trailer.align as usize != layout.align() {
+        panic!(
+            "heap corruption: {:#x} ({}) was allocated as {} bytes aligned to {} but freed as {} bytes aligned to {}",
+            addr,
+            kind,
+            trailer.size,
+            trailer.align,
+            layout.size(),
+            layout.align()
+        );
+    }
+    if red_zone.iter().any(|&b| b != RED_ZONE_BYTE) {
+        panic!(
+            "heap corruption: write past the end of {:#x} ({}, {} bytes)",
+            addr,
+            kind,
+            layout.size()
+        );
+    }
+    trailer.state = FREED;
+}
@@ -0,0 +1,171 @@
+use super::slab::CLASS_COUNT;
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A snapshot of the global allocator's bookkeeping,
+/// see `allocator::stats`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Successful allocations
+    pub allocs: usize,
+    /// Deallocations
+    pub frees: usize,
+    /// Allocations that returned null
+    pub failed: usize,
+    /// Bytes currently allocated
+    pub live_bytes: usize,
+    /// The most bytes that were ever allocated at once
+    pub peak_bytes: usize,
+    /// Bytes currently mapped for the heap
+    pub heap_size: usize,
+    pub backend: BackendStats,
+}
+
+impl Stats {
+    /// The number of allocations that haven't been freed
+    pub fn live_allocs(&self) -> usize {
+        self.allocs - self.frees
+    }
+}
+
+/// The part of `Stats` only a backend knows about,
+/// anything a backend doesn't track is left at 0
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackendStats {
+    /// Bytes allocated from the fallback heap, this
+    /// includes whole slabs
+    pub fallback_used: usize,
+    /// The high-water mark of `fallback_used`
+    pub fallback_peak: usize,
+    pub classes: [ClassStats; CLASS_COUNT],
+}
+
+impl BackendStats {
+    pub const fn new() -> Self {
+        Self {
+            fallback_used: 0,
+            fallback_peak: 0,
+            classes: [ClassStats {
+                block_size: 0,
+                allocs: 0,
+                frees: 0,
+                slabs: 0,
+            }; CLASS_COUNT],
+        }
+    }
+
+    pub(super) fn fallback_alloc(&mut self, size: usize) {
+        self.fallback_used += size;
+        self.fallback_peak = self.fallback_peak.max(self.fallback_used);
+    }
+
+    pub(super) fn fallback_dealloc(&mut self, size: usize) {
+        self.fallback_used -= size;
+    }
+}
+
+/// The activity of a single slab size class
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    /// The slabs currently taken from the fallback heap
+    pub slabs: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "allocs: {} frees: {} live: {} failed: {}",
+            self.allocs,
+            self.frees,
+            self.live_allocs(),
+            self.failed
+        )?;
+        writeln!(
+            f,
+            "live bytes: {} peak bytes: {} heap size: {}",
+            self.live_bytes, self.peak_bytes, self.heap_size
+        )?;
+        write!(
+            f,
+            "fallback used: {} fallback peak: {}",
+            self.backend.fallback_used, self.backend.fallback_peak
+        )?;
+        for class in self.backend.classes.iter().filter(|c| c.block_size > 0) {
+            write!(
+                f,
+                "\n{:>5}: allocs: {} frees: {} slabs: {}",
+                class.block_size, class.allocs, class.frees, class.slabs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The counters `Locked` keeps for every backend, these
+/// are only updated with the allocator locked
+pub(super) struct Counts {
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+    failed: AtomicUsize,
+    live: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Counts {
+    pub const fn new() -> Self {
+        Self {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn alloc(&self, size: usize) {
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        let live = self.live.fetch_add(size, Ordering::Relaxed) + size;
+        if live > self.peak.load(Ordering::Relaxed) {
+            self.peak.store(live, Ordering::Relaxed);
+        }
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dealloc(&self, size: usize) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.live.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn fill(&self, stats: &mut Stats) {
+        stats.allocs = self.allocs.load(Ordering::Relaxed);
+        stats.frees = self.frees.load(Ordering::Relaxed);
+        stats.failed = self.failed.load(Ordering::Relaxed);
+        stats.live_bytes = self.live.load(Ordering::Relaxed);
+        stats.peak_bytes = self.peak.load(Ordering::Relaxed);
+    }
+}
+
+/// Run `f` and panic if it left anything allocated
+/// that was allocated while it ran
+pub fn assert_no_leaks<F: FnOnce()>(f: F) {
+    let before = super::stats();
+    f();
+    let after = super::stats();
+    if after.live_allocs() != before.live_allocs() || after.live_bytes != before.live_bytes {
+        panic!(
+            "leaked {} allocations ({} bytes)\n{}",
+            after.live_allocs() as isize - before.live_allocs() as isize,
+            after.live_bytes as isize - before.live_bytes as isize,
+            after,
+        );
+    }
+}
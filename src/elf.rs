@@ -0,0 +1,285 @@
+//! Parsing ELF64 executables, see `load` for
+//! turning one into a program that can run.
+//!
+//! Only statically linked x86_64 executables (`ET_EXEC`)
+//! are supported
+use core::{convert::TryInto, fmt};
+
+mod loader;
+
+pub use loader::{load, Program, STACK_SIZE, STACK_TOP};
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// Why a binary couldn't be parsed or loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Shorter than the header, or a table runs off the end
+    Truncated,
+    BadMagic,
+    /// Not `ELFCLASS64`
+    NotElf64,
+    /// Not little endian
+    BadEndian,
+    BadVersion,
+    /// Not an `ET_EXEC` executable
+    NotExecutable,
+    /// Not built for x86_64
+    BadMachine,
+    /// The program header entries aren't the size we expect
+    BadProgramHeader,
+    /// A segment's file size is larger than its memory size
+    BadSegmentSize,
+    /// A segment's address and offset don't agree on
+    /// their position within a page
+    MisalignedSegment,
+    /// A segment would land outside of user memory
+    SegmentOutOfRange,
+    NoLoadSegments,
+    /// The entry point isn't in an executable segment
+    BadEntry,
+    /// argv and envp don't fit on the stack
+    ArgsTooLarge,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "the file is truncated"),
+            Self::BadMagic => write!(f, "not an ELF file"),
+            Self::NotElf64 => write!(f, "not a 64 bit ELF file"),
+            Self::BadEndian => write!(f, "not a little endian ELF file"),
+            Self::BadVersion => write!(f, "unknown ELF version"),
+            Self::NotExecutable => write!(f, "not an executable"),
+            Self::BadMachine => write!(f, "not built for x86_64"),
+            Self::BadProgramHeader => write!(f, "unexpected program header size"),
+            Self::BadSegmentSize => write!(f, "a segment is larger in the file than in memory"),
+            Self::MisalignedSegment => write!(f, "a segment's address and offset are misaligned"),
+            Self::SegmentOutOfRange => write!(f, "a segment is outside of user memory"),
+            Self::NoLoadSegments => write!(f, "there is nothing to load"),
+            Self::BadEntry => write!(f, "the entry point is not in an executable segment"),
+            Self::ArgsTooLarge => write!(f, "the arguments don't fit on the stack"),
+        }
+    }
+}
+
+/// An ELF64 file whose headers have been checked
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+/// A program header
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl Segment {
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.mem_size
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// Check the ELF header and program header table
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::BadEndian);
+        }
+        if data[6] != EV_CURRENT {
+            return Err(ElfError::BadVersion);
+        }
+        if u16_at(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if u16_at(data, 18) != EM_X86_64 {
+            return Err(ElfError::BadMachine);
+        }
+        let phnum = usize::from(u16_at(data, 56));
+        if phnum > 0 && usize::from(u16_at(data, 54)) != PHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let phoff = u64_at(data, 32);
+        let table_end = phoff
+            .checked_add((phnum * PHDR_SIZE) as u64)
+            .ok_or(ElfError::Truncated)?;
+        if table_end > data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let elf = Self {
+            data,
+            entry: u64_at(data, 24),
+            phoff: phoff as usize,
+            phnum,
+        };
+        for seg in elf.segments() {
+            elf.check(&seg)?;
+        }
+        Ok(elf)
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let (data, phoff) = (self.data, self.phoff);
+        (0..self.phnum).map(move |i| {
+            let h = phoff + i * PHDR_SIZE;
+            Segment {
+                kind: u32_at(data, h),
+                flags: u32_at(data, h + 4),
+                offset: u64_at(data, h + 8),
+                vaddr: u64_at(data, h + 16),
+                file_size: u64_at(data, h + 32),
+                mem_size: u64_at(data, h + 40),
+                align: u64_at(data, h + 48),
+            }
+        })
+    }
+
+    /// The `PT_LOAD` segments
+    pub fn loadable(&self) -> impl Iterator<Item = Segment> + 'a {
+        self.segments().filter(|s| s.kind == PT_LOAD)
+    }
+
+    /// The bytes of `seg` that come from the file
+    pub fn file_bytes(&self, seg: &Segment) -> &'a [u8] {
+        let start = seg.offset as usize;
+        &self.data[start..start + seg.file_size as usize]
+    }
+
+    fn check(&self, seg: &Segment) -> Result<(), ElfError> {
+        if seg.kind != PT_LOAD {
+            return Ok(());
+        }
+        let file_end = seg
+            .offset
+            .checked_add(seg.file_size)
+            .ok_or(ElfError::Truncated)?;
+        if file_end > self.data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        if seg.file_size > seg.mem_size {
+            return Err(ElfError::BadSegmentSize);
+        }
+        if seg.align > 1 && seg.vaddr % seg.align != seg.offset % seg.align {
+            return Err(ElfError::MisalignedSegment);
+        }
+        Ok(())
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    /// A header with one `PT_LOAD` segment covering the whole file
+    fn minimal() -> [u8; EHDR_SIZE + PHDR_SIZE] {
+        let mut f = [0u8; EHDR_SIZE + PHDR_SIZE];
+        f[..4].copy_from_slice(b"\x7fELF");
+        f[4] = ELFCLASS64;
+        f[5] = ELFDATA2LSB;
+        f[6] = EV_CURRENT;
+        f[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        f[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        f[24..32].copy_from_slice(&0x1000_0000_0078u64.to_le_bytes());
+        f[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        f[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        f[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let h = EHDR_SIZE;
+        f[h..h + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        f[h + 4..h + 8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        f[h + 16..h + 24].copy_from_slice(&0x1000_0000_0000u64.to_le_bytes());
+        f[h + 32..h + 40].copy_from_slice(&((EHDR_SIZE + PHDR_SIZE) as u64).to_le_bytes());
+        f[h + 40..h + 48].copy_from_slice(&0x1000u64.to_le_bytes());
+        f[h + 48..h + 56].copy_from_slice(&0x1000u64.to_le_bytes());
+        f
+    }
+
+    #[kern_test]
+    fn test_parse_minimal() {
+        let f = minimal();
+        let elf = Elf::parse(&f).unwrap();
+        assert_eq!(elf.entry, 0x1000_0000_0078);
+        let seg = elf.loadable().next().unwrap();
+        assert!(seg.executable());
+        assert!(!seg.writable());
+        assert!(seg.contains(elf.entry));
+        assert_eq!(elf.file_bytes(&seg).len(), f.len());
+    }
+
+    #[kern_test]
+    fn test_parse_errors() {
+        assert_eq!(Elf::parse(&[0; 10]).unwrap_err(), ElfError::Truncated);
+        let mut f = minimal();
+        f[0] = 0;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::BadMagic);
+        let mut f = minimal();
+        f[4] = 1;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::NotElf64);
+        let mut f = minimal();
+        f[16] = 3;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::NotExecutable);
+        let mut f = minimal();
+        f[18] = 3;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::BadMachine);
+        let mut f = minimal();
+        f[56] = 200;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::Truncated);
+        let mut f = minimal();
+        f[EHDR_SIZE + 40] = 0;
+        f[EHDR_SIZE + 41] = 0;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::BadSegmentSize);
+        let mut f = minimal();
+        f[EHDR_SIZE + 8] = 1;
+        assert_eq!(Elf::parse(&f).unwrap_err(), ElfError::Truncated);
+    }
+}
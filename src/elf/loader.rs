@@ -0,0 +1,151 @@
+//! Loading an `Elf` into its own address space
+use super::{Elf, ElfError, Segment};
+use crate::{
+    error::Error,
+    memory::AddressSpace,
+    thread,
+    user::{USER_END, USER_START},
+};
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::{
+    registers::control::Cr3, structures::paging::PageTableFlags as Flags, VirtAddr,
+};
+
+/// One past the top of every program's stack, the
+/// page above it is left unmapped
+pub const STACK_TOP: u64 = USER_END - 0x1000;
+pub const STACK_SIZE: u64 = 64 * 1024;
+/// How much of the stack argv and envp can use
+const MAX_ARGS_SIZE: usize = STACK_SIZE as usize / 2;
+const PAGE_SIZE: u64 = 4096;
+
+/// A loaded program, ready to `run`
+pub struct Program {
+    pub space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    /// The initial stack pointer, pointing at `argc`
+    pub stack: VirtAddr,
+}
+
+/// Load the executable in `data` into a fresh address
+/// space, with a stack holding `argv` and `envp`
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, Error> {
+    let elf = Elf::parse(data)?;
+    check_layout(&elf)?;
+    let image = stack_image(argv, envp)?;
+    let mut space = AddressSpace::new()?;
+    for seg in elf.loadable() {
+        let start = VirtAddr::new(seg.vaddr);
+        // map writable so the contents can be copied in
+        space.map(start, seg.mem_size, Flags::WRITABLE)?;
+        space.write(start, elf.file_bytes(&seg))?;
+    }
+    for seg in elf.loadable() {
+        space.protect(VirtAddr::new(seg.vaddr), seg.mem_size, page_flags(&elf, seg))?;
+    }
+    let stack = VirtAddr::new(STACK_TOP - image.len() as u64);
+    let flags = Flags::WRITABLE | Flags::NO_EXECUTE;
+    space.map(VirtAddr::new(STACK_TOP - STACK_SIZE), STACK_SIZE, flags)?;
+    space.write(stack, &image)?;
+    Ok(Program {
+        space: Arc::new(space),
+        entry: VirtAddr::new(elf.entry),
+        stack,
+    })
+}
+
+impl Program {
+    /// Switch to the program's address space, run it until
+    /// it exits and switch back, returns the exit code.
+    ///
+    /// Once `thread::init` has run the address space is kept
+    /// across context switches, before that it is only loaded
+    /// into `Cr3` for as long as the program runs
+    pub unsafe fn run(&self) -> u64 {
+        if !thread::is_started() {
+            let (previous, flags) = Cr3::read();
+            self.space.activate();
+            let code = crate::user::run(self.entry, self.stack);
+            Cr3::write(previous, flags);
+            return code;
+        }
+        let previous = thread::set_address_space(Some(self.space.clone()));
+        let code = crate::user::run(self.entry, self.stack);
+        thread::set_address_space(previous);
+        code
+    }
+}
+
+fn check_layout(elf: &Elf) -> Result<(), ElfError> {
+    let mut any = false;
+    for seg in elf.loadable() {
+        any = true;
+        let end = seg
+            .vaddr
+            .checked_add(seg.mem_size)
+            .ok_or(ElfError::SegmentOutOfRange)?;
+        if seg.vaddr < USER_START || end > STACK_TOP - STACK_SIZE {
+            return Err(ElfError::SegmentOutOfRange);
+        }
+    }
+    if !any {
+        return Err(ElfError::NoLoadSegments);
+    }
+    if !elf.loadable().any(|s| s.executable() && s.contains(elf.entry)) {
+        return Err(ElfError::BadEntry);
+    }
+    Ok(())
+}
+
+/// The flags for the pages of `seg`, segments can share a page
+/// so each page gets the permissions of every segment on it
+fn page_flags(elf: &Elf, seg: Segment) -> Flags {
+    let first = seg.vaddr & !(PAGE_SIZE - 1);
+    let last = (seg.vaddr + seg.mem_size.max(1) - 1) & !(PAGE_SIZE - 1);
+    let mut flags = Flags::NO_EXECUTE;
+    let touching = elf.loadable().filter(|s| {
+        let s_first = s.vaddr & !(PAGE_SIZE - 1);
+        let s_last = (s.vaddr + s.mem_size.max(1) - 1) & !(PAGE_SIZE - 1);
+        s_first <= last && first <= s_last
+    });
+    for s in touching {
+        if s.writable() {
+            flags |= Flags::WRITABLE;
+        }
+        if s.executable() {
+            flags.remove(Flags::NO_EXECUTE);
+        }
+    }
+    flags
+}
+
+/// The System V initial stack: argc, argv, envp and
+/// an empty aux vector, followed by the strings
+fn stack_image(argv: &[&str], envp: &[&str]) -> Result<Vec<u8>, ElfError> {
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let strings = (strings + 15) & !15;
+    let mut words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+    // rsp has to be 16 byte aligned
+    words += words % 2;
+    let size = words * 8 + strings;
+    if size > MAX_ARGS_SIZE {
+        return Err(ElfError::ArgsTooLarge);
+    }
+    let base = STACK_TOP - size as u64;
+    let mut image = alloc::vec![0u8; size];
+    let mut vector: Vec<u64> = Vec::with_capacity(words);
+    vector.push(argv.len() as u64);
+    let mut at = words * 8;
+    for list in &[argv, envp] {
+        for s in list.iter() {
+            image[at..at + s.len()].copy_from_slice(s.as_bytes());
+            vector.push(base + at as u64);
+            at += s.len() + 1;
+        }
+        vector.push(0);
+    }
+    for (i, word) in vector.iter().enumerate() {
+        image[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    Ok(image)
+}
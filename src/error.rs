@@ -1,8 +1,23 @@
-use x86_64::structures::paging::mapper::MapToError;
+use crate::elf::ElfError;
+use x86_64::{structures::paging::mapper::MapToError, VirtAddr};
 
 pub enum Error {
     OutOfFrames,
     MapTo(MapToError),
+    FaultRangeClaimed,
+    TooManyFaultClaims,
+    MemoryNotInstalled,
+    NoRsdp,
+    BadAcpiChecksum(&'static str),
+    NoAcpiTable(&'static str),
+    NoIoApic,
+    TooManyTimers,
+    TooManyThreads,
+    NotUserMemory,
+    Elf(ElfError),
+    PageNotMapped(VirtAddr),
+    NotAligned,
+    TooManyStacks,
 }
 
 impl core::fmt::Display for Error {
@@ -10,6 +25,20 @@ impl core::fmt::Display for Error {
         match self {
             Self::OutOfFrames => write!(f, "Attempted to map a frame but no frames were available"),
             Self::MapTo(inner) => write!(f, "{:?}", inner),
+            Self::FaultRangeClaimed => write!(f, "Attempted to claim a range that overlaps an existing page fault claim"),
+            Self::TooManyFaultClaims => write!(f, "No page fault claims are available"),
+            Self::MemoryNotInstalled => write!(f, "Attempted to map memory before the kernel page table was installed"),
+            Self::NoRsdp => write!(f, "Unable to find the ACPI RSDP"),
+            Self::BadAcpiChecksum(sig) => write!(f, "ACPI table {} failed its checksum", sig),
+            Self::NoAcpiTable(sig) => write!(f, "Unable to find the ACPI table {}", sig),
+            Self::NoIoApic => write!(f, "No IOAPIC handles the requested interrupt"),
+            Self::TooManyTimers => write!(f, "No timer callback slots are available"),
+            Self::TooManyThreads => write!(f, "No thread slots are available"),
+            Self::NotUserMemory => write!(f, "The address range is outside of user memory"),
+            Self::Elf(inner) => write!(f, "Unable to load ELF file: {}", inner),
+            Self::PageNotMapped(addr) => write!(f, "The page at {:?} is not mapped", addr),
+            Self::NotAligned => write!(f, "The address or size is not aligned to the page size"),
+            Self::TooManyStacks => write!(f, "No kernel stack slots are available"),
         }
     }
 }
@@ -24,4 +53,9 @@ impl From<MapToError> for Error {
     fn from(other: MapToError) -> Self {
         Self::MapTo(other)
     }
-}
\ No newline at end of file
+}
+impl From<ElfError> for Error {
+    fn from(other: ElfError) -> Self {
+        Self::Elf(other)
+    }
+}
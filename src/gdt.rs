@@ -1,25 +1,104 @@
+use crate::{
+    error::Error,
+    memory::{Stack, StackName},
+};
+use core::cell::UnsafeCell;
 use x86_64::{
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
 };
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub const SIZE: usize = 4096;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+/// The size of the static IST stacks used until `init_stacks`
+pub const SIZE: usize = 8 * 1024;
+/// The size of the stacks `init_stacks` gives the IST
+pub const IST_STACK_SIZE: usize = 16 * 1024;
+/// The IST entries in use
+const IST_INDEXES: &[u16] = &[
+    DOUBLE_FAULT_IST_INDEX,
+    NMI_IST_INDEX,
+    MACHINE_CHECK_IST_INDEX,
+    PAGE_FAULT_IST_INDEX,
+];
+/// The size of the stack the CPU switches to when
+/// an interrupt or syscall comes from ring 3, it starts
+/// on a plain static until `init_stacks`
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Present, writable, a code/data segment. Long mode ignores
+/// everything else about a data segment
+const KERNEL_DATA: u64 = (1 << 41) | (1 << 44) | (1 << 47);
+
+/// The TSS, wrapped so the ring 0 stack
+/// can be changed after it is loaded
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// The CPU only reads the TSS on a ring change and we
+// only write it with interrupts disabled
+unsafe impl Sync for Tss {}
+
 lazy_static::lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; SIZE] = [0; SIZE];
+        static mut IST_STACKS: [[u8; SIZE]; IST_INDEXES.len()] = [[0; SIZE]; IST_INDEXES.len()];
+        for (i, &index) in IST_INDEXES.iter().enumerate() {
+            let start = VirtAddr::from_ptr(unsafe { &IST_STACKS[i] });
+            tss.interrupt_stack_table[usize::from(index)] = start + SIZE;
+        }
+        tss.privilege_stack_table[0] = {
+            static mut STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
             let start = VirtAddr::from_ptr(unsafe { &STACK });
-            let end = start + SIZE;
-            end
+            start + KERNEL_STACK_SIZE
         };
-        tss
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+/// The stack the CPU switches to when entering
+/// the kernel from ring 3
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] }
+}
+
+/// Change the stack used when entering the kernel from
+/// ring 3, this is also the stack syscalls run on
+pub fn set_kernel_stack(top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = top;
+    });
+    crate::syscall::set_stack(top);
+}
+
+/// The top of the stack the CPU switches to for
+/// interrupts with the IST entry `index`
+pub fn ist_stack(index: u16) -> VirtAddr {
+    unsafe { (*TSS.0.get()).interrupt_stack_table[usize::from(index)] }
+}
+
+/// Move the IST entries and the ring 0 stack to stacks with guard
+/// pages so an overflow is caught and named, this needs
+/// `memory::install`. The TSS is loaded before there is memory to
+/// map so they start out on plain statics
+pub fn init_stacks() -> Result<(), Error> {
+    for &index in IST_INDEXES {
+        let stack = Stack::new(StackName::Ist(index), IST_STACK_SIZE)?;
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*TSS.0.get()).interrupt_stack_table[usize::from(index)] = stack.top();
+        });
+        // the CPU can switch to it whenever it likes from now on
+        core::mem::forget(stack);
+    }
+    let stack = Stack::new(StackName::Kernel, KERNEL_STACK_SIZE)?;
+    set_kernel_stack(stack.top());
+    core::mem::forget(stack);
+    Ok(())
+}
+
 struct Gdt {
     table: GlobalDescriptorTable,
     selectors: Selectors,
@@ -34,31 +113,52 @@ impl Gdt {
     }
 }
 
-struct Selectors {
-    code: SegmentSelector,
-    tss: SegmentSelector,
-}
-
-impl Selectors {
-    pub fn new(code: SegmentSelector, tss: SegmentSelector) -> Self {
-        Self { code, tss }
-    }
+/// The GDT is laid out so `sysret` finds the user segments,
+/// it loads SS from the STAR base + 8 and CS from base + 16
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 
 lazy_static::lazy_static! {
     static ref GDT: Gdt = {
         let mut g = GlobalDescriptorTable::new();
-        let code = g.add_entry(Descriptor::kernel_code_segment());
-        let tss = g.add_entry(Descriptor::tss_segment(&TSS));
-        let s = Selectors::new(code, tss);
+        let kernel_code = g.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = g.add_entry(Descriptor::UserSegment(KERNEL_DATA));
+        let user_data = g.add_entry(Descriptor::user_data_segment());
+        let user_code = g.add_entry(Descriptor::user_code_segment());
+        let tss = g.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+        let s = Selectors {
+            kernel_code,
+            kernel_data,
+            user_data: SegmentSelector::new(user_data.index(), PrivilegeLevel::Ring3),
+            user_code: SegmentSelector::new(user_code.index(), PrivilegeLevel::Ring3),
+            tss,
+        };
         Gdt::new(g, s)
     };
 }
+
+/// The segment selectors, user selectors have their RPL set
+pub fn selectors() -> Selectors {
+    GDT.selectors
+}
+
 pub fn init() {
-    use x86_64::instructions::{segmentation::set_cs, tables::load_tss};
+    use x86_64::instructions::{
+        segmentation::{load_ds, load_es, load_ss, set_cs},
+        tables::load_tss,
+    };
     GDT.load();
     unsafe {
-        set_cs(GDT.selectors.code);
+        set_cs(GDT.selectors.kernel_code);
+        load_ss(GDT.selectors.kernel_data);
+        load_ds(GDT.selectors.kernel_data);
+        load_es(GDT.selectors.kernel_data);
         load_tss(GDT.selectors.tss);
     }
 }
@@ -1,26 +1,60 @@
+use crate::{gdt, sync::IrqSpinlock};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
-use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod apic;
+pub mod exceptions;
+pub mod page_fault;
+
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
+        use exceptions::*;
         let mut i = InterruptDescriptorTable::new();
+        i.divide_error.set_handler_fn(divide_error);
+        i.debug.set_handler_fn(debug);
         i.breakpoint.set_handler_fn(breakpoint);
+        i.overflow.set_handler_fn(overflow);
+        i.bound_range_exceeded.set_handler_fn(bound_range_exceeded);
+        i.invalid_opcode.set_handler_fn(invalid_opcode);
+        i.device_not_available.set_handler_fn(device_not_available);
+        // these can arrive when the current stack is unusable, so
+        // each gets a known good stack of its own. A fault nested in
+        // one of them starts over at the top of the same stack, so
+        // their handlers must not fault
         unsafe {
             i.double_fault
                 .set_handler_fn(double_fault)
-                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            i.non_maskable_interrupt
+                .set_handler_fn(non_maskable_interrupt)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            i.machine_check
+                .set_handler_fn(machine_check)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            i.page_fault
+                .set_handler_fn(page_fault::page_fault)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
+        i.invalid_tss.set_handler_fn(invalid_tss);
+        i.segment_not_present.set_handler_fn(segment_not_present);
+        i.stack_segment_fault.set_handler_fn(stack_segment_fault);
+        i.general_protection_fault.set_handler_fn(general_protection_fault);
+        i.x87_floating_point.set_handler_fn(x87_floating_point);
+        i.alignment_check.set_handler_fn(alignment_check);
+        i.simd_floating_point.set_handler_fn(simd_floating_point);
+        i.virtualization.set_handler_fn(virtualization);
+        i.security_exception.set_handler_fn(security_exception);
         i[InterruptIndex::Timer.as_usize()].set_handler_fn(timer);
         i[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard);
+        i[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious);
         i
     };
 }
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub const PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinlock<ChainedPics> =
+    IrqSpinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 pub fn init_idt() {
     IDT.load();
@@ -32,6 +66,18 @@ pub fn init_pics() {
     }
 }
 
+/// Tell whichever interrupt controller is in use
+/// that the interrupt `index` has been handled
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -48,50 +94,25 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn breakpoint(frame: &mut InterruptStackFrame) {
-    crate::println!("EXCEPTION BREAKPOINT");
-    crate::println!("{:#?}", frame);
-}
-
-extern "x86-interrupt" fn double_fault(frame: &mut InterruptStackFrame, code: u64) -> ! {
-    panic!("DOUBLE FAULT ({}):\n{:#?}", code, frame);
-}
 extern "x86-interrupt" fn timer(_frame: &mut InterruptStackFrame) {
-    crate::print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::timer::tick();
+    end_of_interrupt(InterruptIndex::Timer);
+    // this may not return until the interrupted
+    // thread is scheduled again
+    crate::thread::preempt();
 }
 extern "x86-interrupt" fn keyboard(_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                layouts::Us104Key,
-                ScancodeSet1,
-                HandleControl::MapLettersToUnicode
-            ));
-    }
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_ev)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_ev) {
-            match key {
-                DecodedKey::Unicode(ch) => crate::print!("{}", ch),
-                DecodedKey::RawKey(ch) => crate::print!("{:?}", ch),
-            }
-        }
-    }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    crate::keyboard::add_scancode(scancode);
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
 
+/// Spurious interrupts from the Local APIC
+/// must not be acknowledged
+extern "x86-interrupt" fn spurious(_frame: &mut InterruptStackFrame) {}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -101,4 +122,41 @@ mod test {
     fn test_breakpoint() {
         x86_64::instructions::interrupts::int3();
     }
+
+    #[kern_test]
+    fn test_overlapping_fault_claims() {
+        use crate::error::Error;
+        use x86_64::VirtAddr;
+        fn unhandled(_: &page_fault::PageFault) -> page_fault::Resolution {
+            page_fault::Resolution::Unhandled
+        }
+        let start = VirtAddr::new(0x_5555_0000_0000);
+        let id = page_fault::claim(start, start + 0x2000u64, unhandled).unwrap();
+        match page_fault::claim(start + 0x1000u64, start + 0x3000u64, unhandled) {
+            Err(Error::FaultRangeClaimed) => (),
+            other => panic!("expected overlapping claim to fail: {:?}", other),
+        }
+        assert!(page_fault::release(id));
+        let id = page_fault::claim(start + 0x1000u64, start + 0x3000u64, unhandled).unwrap();
+        assert!(page_fault::release(id));
+    }
+
+    #[kern_test]
+    fn test_stale_fault_claim_release() {
+        use x86_64::VirtAddr;
+        fn unhandled(_: &page_fault::PageFault) -> page_fault::Resolution {
+            page_fault::Resolution::Unhandled
+        }
+        let start = VirtAddr::new(0x_5555_0001_0000);
+        let stale = page_fault::claim(start, start + 0x1000u64, unhandled).unwrap();
+        assert!(page_fault::release(stale));
+        // most likely takes the same slot
+        let id = page_fault::claim(start, start + 0x1000u64, unhandled).unwrap();
+        assert!(!page_fault::release(stale));
+        match page_fault::claim(start, start + 0x1000u64, unhandled) {
+            Err(crate::error::Error::FaultRangeClaimed) => (),
+            other => panic!("stale release freed the new claim: {:?}", other),
+        }
+        assert!(page_fault::release(id));
+    }
 }
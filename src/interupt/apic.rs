@@ -0,0 +1,196 @@
+//! The Local APIC and IOAPIC, once `init` succeeds these
+//! replace the 8259 PIC, which is left remapped but fully masked
+use super::{InterruptIndex, PIC_1_OFFSET};
+use crate::{
+    acpi::{IoApicInfo, Madt},
+    error::Error,
+    memory, timer,
+};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::{
+    instructions::port::Port,
+    registers::model_specific::Msr,
+    VirtAddr,
+};
+
+/// The vector the Local APIC raises for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets
+const ID: u64 = 0x20;
+const EOI: u64 = 0xb0;
+const SPURIOUS: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL: u64 = 0x380;
+const TIMER_CURRENT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// The virtual address of the Local APIC registers,
+/// 0 until `init` succeeds
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer ticks per `timer::HZ` period
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// If the APIC has taken over from the PIC
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+/// Find the interrupt controllers in the MADT, route the
+/// keyboard through the IOAPIC, start the Local APIC timer
+/// and mask off the PIC.
+///
+/// This needs the kernel memory to be installed (see
+/// `allocator::init_heap`) to map the APIC registers, if
+/// it fails the PIC is left alone
+pub fn init() -> Result<(), Error> {
+    use x86_64::instructions::interrupts::without_interrupts;
+    let madt = Madt::find()?;
+    let local = memory::map_mmio(madt.local_apic, 4096)?;
+    without_interrupts(|| -> Result<(), Error> {
+        let lapic = LocalApic(local);
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+            lapic.write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+        }
+        let keyboard = InterruptIndex::Keyboard.as_u8() - PIC_1_OFFSET;
+        route_isa(&madt, keyboard, InterruptIndex::Keyboard.as_u8(), lapic.id())?;
+        mask_pic();
+        let count = calibrate_timer(&lapic) / timer::HZ;
+        TIMER_COUNT.store(count, Ordering::SeqCst);
+        unsafe {
+            lapic.write(TIMER_DIVIDE, DIVIDE_BY_16);
+            lapic.write(
+                LVT_TIMER,
+                TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+            );
+            lapic.write(TIMER_INITIAL, count);
+        }
+        LOCAL_APIC.store(local.as_u64(), Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// Signal the end of an interrupt to the Local APIC
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    if base != 0 {
+        unsafe {
+            LocalApic(VirtAddr::new(base)).write(EOI, 0);
+        }
+    }
+}
+
+struct LocalApic(VirtAddr);
+
+impl LocalApic {
+    fn reg(&self, offset: u64) -> *mut u32 {
+        (self.0 + offset).as_mut_ptr()
+    }
+    unsafe fn read(&self, offset: u64) -> u32 {
+        core::ptr::read_volatile(self.reg(offset))
+    }
+    unsafe fn write(&self, offset: u64, value: u32) {
+        core::ptr::write_volatile(self.reg(offset), value);
+    }
+    fn id(&self) -> u8 {
+        (unsafe { self.read(ID) } >> 24) as u8
+    }
+}
+
+struct IoApic(VirtAddr);
+
+impl IoApic {
+    unsafe fn write(&self, reg: u32, value: u32) {
+        core::ptr::write_volatile(self.0.as_mut_ptr::<u32>(), reg);
+        core::ptr::write_volatile((self.0 + 0x10u64).as_mut_ptr::<u32>(), value);
+    }
+    /// Point redirection entry `entry` at `vector`
+    /// on the Local APIC `dest`
+    unsafe fn redirect(&self, entry: u32, vector: u8, dest: u8, active_low: bool, level: bool) {
+        let mut low = u32::from(vector);
+        if active_low {
+            low |= 1 << 13;
+        }
+        if level {
+            low |= 1 << 15;
+        }
+        self.write(0x10 + entry * 2 + 1, u32::from(dest) << 24);
+        self.write(0x10 + entry * 2, low);
+    }
+}
+
+/// Send ISA interrupt `irq` to `vector`, honoring
+/// any override in the MADT
+fn route_isa(madt: &Madt, irq: u8, vector: u8, dest: u8) -> Result<(), Error> {
+    let (gsi, active_low, level) = match madt.isa_override(irq) {
+        Some(o) => (o.gsi, o.active_low(), o.level_triggered()),
+        None => (u32::from(irq), false, false),
+    };
+    let info: IoApicInfo = madt
+        .io_apics
+        .iter()
+        .flatten()
+        .filter(|io| io.gsi_base <= gsi)
+        .max_by_key(|io| io.gsi_base)
+        .copied()
+        .ok_or(Error::NoIoApic)?;
+    let io = IoApic(memory::map_mmio(info.addr, 0x20)?);
+    unsafe {
+        io.redirect(gsi - info.gsi_base, vector, dest, active_low, level);
+    }
+    Ok(())
+}
+
+/// Mask every line on both PICs, they stay remapped
+/// by `init_pics` so a spurious interrupt from them
+/// can't look like an exception
+fn mask_pic() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Count how many Local APIC timer ticks (divided by 16)
+/// pass in a second, using PIT channel 2 as the reference
+fn calibrate_timer(lapic: &LocalApic) -> u32 {
+    const PIT_HZ: u32 = 1_193_182;
+    const CALIBRATION_MS: u32 = 10;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    let reload = PIT_HZ * CALIBRATION_MS / 1000;
+    unsafe {
+        // enable the channel 2 gate with the speaker off
+        let g = gate.read();
+        gate.write((g & !0b10) | 0b1);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(reload as u8);
+        channel2.write((reload >> 8) as u8);
+        // restart the count by toggling the gate
+        let g = gate.read();
+        gate.write(g & !1);
+        gate.write(g | 1);
+        lapic.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        lapic.write(LVT_TIMER, LVT_MASKED);
+        lapic.write(TIMER_INITIAL, core::u32::MAX);
+        // bit 5 goes high when the PIT reaches 0
+        while gate.read() & 0b10_0000 == 0 {}
+        let elapsed = core::u32::MAX - lapic.read(TIMER_CURRENT);
+        lapic.write(TIMER_INITIAL, 0);
+        elapsed * (1000 / CALIBRATION_MS)
+    }
+}
@@ -0,0 +1,214 @@
+//! Handlers for the CPU exception vectors
+//!
+//! Everything but `breakpoint`, `debug` and `non_maskable_interrupt`
+//! is treated as fatal, the handler reports what it can over both
+//! VGA and serial and then stops the machine (or fails the run
+//! when under test).
+//!
+//! Something that needs to know about an NMI or machine check
+//! (a watchdog, a test checking which stack the handler runs on)
+//! can set a `Hook` that the handler calls after reporting
+use crate::sync::IrqSpinlock;
+use core::fmt;
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// Called from inside an exception handler, on its stack
+pub type Hook = fn(&InterruptStackFrame);
+
+static NMI_HOOK: IrqSpinlock<Option<Hook>> = IrqSpinlock::new(None);
+static MACHINE_CHECK_HOOK: IrqSpinlock<Option<Hook>> = IrqSpinlock::new(None);
+
+/// Have `hook` called for every NMI, `None` removes it
+pub fn on_nmi(hook: Option<Hook>) {
+    *NMI_HOOK.lock() = hook;
+}
+
+/// Have `hook` called on a machine check before the machine
+/// stops, `None` removes it
+pub fn on_machine_check(hook: Option<Hook>) {
+    *MACHINE_CHECK_HOOK.lock() = hook;
+}
+
+/// Run the hook in `slot` if there is one. An NMI can arrive
+/// while the hook is being set, that one goes without
+fn run_hook(slot: &IrqSpinlock<Option<Hook>>, frame: &InterruptStackFrame) {
+    let hook = slot.try_lock().and_then(|hook| *hook);
+    if let Some(hook) = hook {
+        hook(frame);
+    }
+}
+
+/// The number of bytes to dump from the faulting instruction,
+/// the longest x86 instruction is 15 bytes
+const INSTRUCTION_BYTES: usize = 15;
+
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        crate::println!($($arg)*);
+        crate::serial_println!($($arg)*);
+    }};
+}
+
+/// The bytes at the faulting instruction pointer, up to the
+/// first one that can't be read without faulting again
+struct InstructionBytes(VirtAddr);
+
+impl InstructionBytes {
+    /// The address of byte `i`, `None` if it is
+    /// non-canonical or unmapped
+    fn byte(&self, i: usize) -> Option<VirtAddr> {
+        let addr = self.0.as_u64().checked_add(i as u64)?;
+        let addr = VirtAddr::try_new(addr).ok()?;
+        // only the first byte of each page needs checking
+        let new_page = i == 0 || addr.as_u64() % 4096 == 0;
+        if new_page && !crate::memory::is_mapped(addr) {
+            return None;
+        }
+        Some(addr)
+    }
+}
+
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in 0..INSTRUCTION_BYTES {
+            let addr = match self.byte(i) {
+                Some(addr) => addr,
+                None if i == 0 => return write!(f, "<unmapped>"),
+                None => return write!(f, " <unmapped>"),
+            };
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            let b = unsafe { addr.as_ptr::<u8>().read_volatile() };
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+fn report(name: impl fmt::Display, frame: &InterruptStackFrame, code: Option<u64>) {
+    match code {
+        Some(code) => report!("EXCEPTION {} (code: {:#x})", name, code),
+        None => report!("EXCEPTION {}", name),
+    }
+    report!("{:#?}", frame);
+    report!(
+        "instruction bytes: {}",
+        InstructionBytes(frame.instruction_pointer)
+    );
+}
+
+/// Report an exception we can't recover from and stop
+fn fatal(name: impl fmt::Display, frame: &InterruptStackFrame, code: Option<u64>) -> ! {
+    // We are never going back to whatever was interrupted,
+    // so if it was holding either output lock we take them
+    // rather than deadlock trying to report
+    unsafe {
+        crate::vga_buffer::WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+    }
+    report(name, frame, code);
+    if crate::is_testing() {
+        crate::exit_qemu(crate::QemuExitCode::Failed);
+    }
+    crate::hlt_loop()
+}
+
+macro_rules! fatal_handler {
+    ($name:ident, $desc:expr) => {
+        pub(super) extern "x86-interrupt" fn $name(frame: &mut InterruptStackFrame) {
+            fatal($desc, frame, None)
+        }
+    };
+    ($name:ident, $desc:expr, code) => {
+        pub(super) extern "x86-interrupt" fn $name(frame: &mut InterruptStackFrame, code: u64) {
+            fatal($desc, frame, Some(code))
+        }
+    };
+}
+
+fatal_handler!(divide_error, "DIVIDE ERROR");
+fatal_handler!(overflow, "OVERFLOW");
+fatal_handler!(bound_range_exceeded, "BOUND RANGE EXCEEDED");
+fatal_handler!(invalid_opcode, "INVALID OPCODE");
+fatal_handler!(device_not_available, "DEVICE NOT AVAILABLE");
+fatal_handler!(x87_floating_point, "X87 FLOATING POINT");
+fatal_handler!(simd_floating_point, "SIMD FLOATING POINT");
+fatal_handler!(virtualization, "VIRTUALIZATION");
+fatal_handler!(invalid_tss, "INVALID TSS", code);
+fatal_handler!(segment_not_present, "SEGMENT NOT PRESENT", code);
+fatal_handler!(stack_segment_fault, "STACK SEGMENT FAULT", code);
+fatal_handler!(general_protection_fault, "GENERAL PROTECTION FAULT", code);
+fatal_handler!(alignment_check, "ALIGNMENT CHECK", code);
+fatal_handler!(security_exception, "SECURITY EXCEPTION", code);
+
+pub(super) extern "x86-interrupt" fn double_fault(
+    frame: &mut InterruptStackFrame,
+    code: u64,
+) -> ! {
+    use x86_64::registers::control::Cr2;
+    // a stack overflow normally reaches the page fault handler
+    // on its own stack, it ends up here if that went wrong too
+    match crate::memory::stack::overflowed(Cr2::read()) {
+        Some(stack) => fatal(
+            format_args!("DOUBLE FAULT: stack overflow in {}", stack),
+            frame,
+            Some(code),
+        ),
+        None => fatal("DOUBLE FAULT", frame, Some(code)),
+    }
+}
+
+pub(super) extern "x86-interrupt" fn machine_check(frame: &mut InterruptStackFrame) -> ! {
+    run_hook(&MACHINE_CHECK_HOOK, frame);
+    fatal("MACHINE CHECK", frame, None)
+}
+
+pub(super) extern "x86-interrupt" fn breakpoint(frame: &mut InterruptStackFrame) {
+    report("BREAKPOINT", frame, None);
+}
+
+pub(super) extern "x86-interrupt" fn debug(frame: &mut InterruptStackFrame) {
+    report("DEBUG", frame, None);
+}
+
+pub(super) extern "x86-interrupt" fn non_maskable_interrupt(frame: &mut InterruptStackFrame) {
+    report("NON MASKABLE INTERRUPT", frame, None);
+    run_hook(&NMI_HOOK, frame);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use core::fmt::Write;
+    use kern_test::kern_test;
+
+    /// Keeps the end of whatever is written to it
+    struct Tail([u8; 16], usize);
+
+    impl Write for Tail {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for &b in s.as_bytes() {
+                self.0.copy_within(1.., 0);
+                self.0[15] = b;
+                self.1 += 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[kern_test]
+    fn test_instruction_bytes_stop_at_hole() {
+        // the last canonical address below the hole
+        let mut tail = Tail([0; 16], 0);
+        let last = VirtAddr::new(0x_7fff_ffff_ffff);
+        write!(tail, "{}", InstructionBytes(last)).unwrap();
+        assert!(tail.0.ends_with(b"<unmapped>"));
+        let mut tail = Tail([0; 16], 0);
+        let here = VirtAddr::new(test_instruction_bytes_stop_at_hole as usize as u64);
+        write!(tail, "{}", InstructionBytes(here)).unwrap();
+        // 15 bytes, two hex digits and a space each
+        assert!(tail.1 >= INSTRUCTION_BYTES * 3 - 1);
+    }
+}
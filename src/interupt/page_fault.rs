@@ -0,0 +1,185 @@
+//! Page fault handling
+//!
+//! Any subsystem that owns a range of virtual memory can
+//! claim it here with a handler that gets the first shot at
+//! resolving faults inside that range (demand paging, guard
+//! pages and the like). Faults nobody resolves end in a panic
+//! with everything we know about the fault.
+use crate::error::Error;
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// The most address ranges that can be claimed at once
+pub const MAX_CLAIMS: usize = 16;
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+/// Bumped for every claim so a stale `ClaimId`
+/// can't release whatever took its slot
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// What a claim's handler did with a fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The fault was fixed, the faulting instruction
+    /// will be retried
+    Resolved,
+    /// The handler couldn't do anything about the fault
+    Unhandled,
+}
+
+pub type FaultHandler = fn(&PageFault) -> Resolution;
+
+/// A decoded page fault
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The address that was accessed, read from `Cr2`
+    pub addr: VirtAddr,
+    /// The address of the faulting instruction
+    pub ip: VirtAddr,
+    pub code: PageFaultErrorCode,
+}
+
+impl PageFault {
+    /// The page was present, this was a protection violation
+    pub fn present(&self) -> bool {
+        self.code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    }
+    /// The access was a write
+    pub fn write(&self) -> bool {
+        self.code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    }
+    /// The access came from ring 3
+    pub fn user(&self) -> bool {
+        self.code.contains(PageFaultErrorCode::USER_MODE)
+    }
+    /// The access was an instruction fetch
+    pub fn instruction_fetch(&self) -> bool {
+        self.code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} of {:#x} at {:#x} ({}, code: {:#x})",
+            if self.user() { "user" } else { "kernel" },
+            if self.instruction_fetch() {
+                "instruction fetch"
+            } else if self.write() {
+                "write"
+            } else {
+                "read"
+            },
+            self.addr.as_u64(),
+            self.ip.as_u64(),
+            if self.present() {
+                "protection violation"
+            } else {
+                "page not present"
+            },
+            self.code.bits(),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Claim {
+    generation: usize,
+    start: VirtAddr,
+    end: VirtAddr,
+    handler: FaultHandler,
+}
+
+impl Claim {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        start < self.end && self.start < end
+    }
+}
+
+/// A handle to a claimed range, pass it to `release`
+/// to give the range back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClaimId {
+    slot: usize,
+    generation: usize,
+}
+
+/// Claim the virtual addresses `start..end`, any page fault
+/// in that range will first be passed to `handler`
+pub fn claim(start: VirtAddr, end: VirtAddr, handler: FaultHandler) -> Result<ClaimId, Error> {
+    use x86_64::instructions::interrupts::without_interrupts;
+    without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        if claims.iter().flatten().any(|c| c.overlaps(start, end)) {
+            return Err(Error::FaultRangeClaimed);
+        }
+        let slot = claims
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::TooManyFaultClaims)?;
+        let generation = GENERATION.fetch_add(1, Ordering::SeqCst);
+        claims[slot] = Some(Claim {
+            generation,
+            start,
+            end,
+            handler,
+        });
+        Ok(ClaimId { slot, generation })
+    })
+}
+
+/// Release a range previously claimed with `claim`, returns
+/// false if it was already released
+pub fn release(id: ClaimId) -> bool {
+    use x86_64::instructions::interrupts::without_interrupts;
+    without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        match claims[id.slot] {
+            Some(c) if c.generation == id.generation => {
+                claims[id.slot] = None;
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+fn find_handler(addr: VirtAddr) -> Option<FaultHandler> {
+    // a fault while the table is locked (say from inside `claim`)
+    // can't be passed on, it is reported like any other
+    let claims = CLAIMS.try_lock()?;
+    claims
+        .iter()
+        .flatten()
+        .find(|c| c.contains(addr))
+        .map(|c| c.handler)
+}
+
+pub(super) extern "x86-interrupt" fn page_fault(
+    frame: &mut InterruptStackFrame,
+    code: PageFaultErrorCode,
+) {
+    let fault = PageFault {
+        addr: Cr2::read(),
+        ip: frame.instruction_pointer,
+        code,
+    };
+    if let Some(handler) = find_handler(fault.addr) {
+        if handler(&fault) == Resolution::Resolved {
+            return;
+        }
+    }
+    panic!("EXCEPTION PAGE FAULT\n{}\n{:#?}", fault, frame);
+}
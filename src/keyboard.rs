@@ -0,0 +1,387 @@
+//! Keyboard input.
+//!
+//! The interrupt handler only pushes raw scancodes onto a
+//! lock-free queue, they are decoded by whoever holds the
+//! `ScancodeStream` (or the `KeyStream` built on top of it).
+//!
+//! The exception is Shift+PageUp and Shift+PageDown, which
+//! scroll the console's history straight from the interrupt
+//! so they still work after a panic when nothing reads keys
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+
+/// The most scancodes that can be waiting
+/// before new ones are dropped
+pub const QUEUE_SIZE: usize = 128;
+
+static QUEUE: ScancodeQueue = ScancodeQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Scancodes that arrived while the queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+/// The scrollback keys as seen by the interrupt handler
+static SCROLL_KEYS: ScrollKeys = ScrollKeys::new();
+
+/// A single producer, single consumer ring buffer,
+/// the interrupt handler pushes and the stream pops.
+///
+/// One slot is always left empty so `head == tail`
+/// only ever means empty
+struct ScancodeQueue {
+    buf: [AtomicU8; QUEUE_SIZE],
+    /// The next slot to pop, only the consumer writes this
+    head: AtomicUsize,
+    /// The next slot to push, only the producer writes this
+    tail: AtomicUsize,
+}
+
+impl ScancodeQueue {
+    const fn new() -> Self {
+        Self {
+            buf: [AtomicU8::new(0); QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        self.buf[tail].store(scancode, Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = self.buf[head].load(Ordering::Relaxed);
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        Some(scancode)
+    }
+}
+
+/// Just enough scancode set 1 decoding to pick out Shift+PageUp
+/// and Shift+PageDown before the scancodes reach the queue.
+/// Only the interrupt handler uses this
+struct ScrollKeys {
+    lshift: AtomicBool,
+    rshift: AtomicBool,
+    /// The last scancode was the `0xe0` prefix, which
+    /// is held back until we know what it is for
+    extended: AtomicBool,
+}
+
+impl ScrollKeys {
+    const EXTENDED: u8 = 0xe0;
+    const BREAK: u8 = 0x80;
+    const LSHIFT: u8 = 0x2a;
+    const RSHIFT: u8 = 0x36;
+    const CTRL: u8 = 0x1d;
+    const ALT: u8 = 0x38;
+    const PAGE_UP: u8 = 0x49;
+    const PAGE_DOWN: u8 = 0x51;
+
+    const fn new() -> Self {
+        Self {
+            lshift: AtomicBool::new(false),
+            rshift: AtomicBool::new(false),
+            extended: AtomicBool::new(false),
+        }
+    }
+
+    /// Act on the scrollback keys, the scancodes of anything else
+    /// are pushed. Any other key press puts the live screen back
+    fn add(&self, scancode: u8) {
+        if scancode == Self::EXTENDED {
+            self.extended.store(true, Ordering::Relaxed);
+            return;
+        }
+        let extended = self.extended.swap(false, Ordering::Relaxed);
+        let shift = self.lshift.load(Ordering::Relaxed) || self.rshift.load(Ordering::Relaxed);
+        let make = scancode & !Self::BREAK;
+        let down = scancode & Self::BREAK == 0;
+        if extended && shift && (make == Self::PAGE_UP || make == Self::PAGE_DOWN) {
+            if down {
+                scroll(make == Self::PAGE_UP);
+            }
+            return;
+        }
+        match make {
+            // shift with 0xe0 is a fake one some keys send
+            Self::LSHIFT if !extended => self.lshift.store(down, Ordering::Relaxed),
+            Self::RSHIFT if !extended => self.rshift.store(down, Ordering::Relaxed),
+            Self::LSHIFT | Self::RSHIFT | Self::CTRL | Self::ALT => (),
+            _ if down => show_live(),
+            _ => (),
+        }
+        if extended {
+            push(Self::EXTENDED);
+        }
+        push(scancode);
+    }
+}
+
+/// Scroll the console a page back or forward. If the
+/// interrupt came in while it was locked the key is lost
+fn scroll(back: bool) {
+    use crate::vga_buffer::{PAGE_LINES, WRITER};
+    if let Some(mut writer) = WRITER.try_lock() {
+        if back {
+            writer.scroll_back(PAGE_LINES);
+        } else {
+            writer.scroll_forward(PAGE_LINES);
+        }
+    }
+}
+
+fn show_live() {
+    if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+        writer.show_live();
+    }
+}
+
+fn push(scancode: u8) {
+    if QUEUE.push(scancode) {
+        WAKER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called from the keyboard interrupt handler with each byte
+/// read from the keyboard, this must not block or allocate
+pub fn add_scancode(scancode: u8) {
+    SCROLL_KEYS.add(scancode);
+}
+
+/// The number of scancodes dropped because
+/// nobody was reading them fast enough
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The raw scancodes from the keyboard, there can
+/// only be one of these
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /// Take the scancode stream, this panics if it
+    /// has already been taken
+    pub fn new() -> Self {
+        if STREAM_TAKEN.swap(true, Ordering::SeqCst) {
+            panic!("ScancodeStream::new should only be called once");
+        }
+        Self { _private: () }
+    }
+
+    /// The next scancode if there is one waiting
+    pub fn try_next(&mut self) -> Option<u8> {
+        QUEUE.pop()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(scancode) = QUEUE.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        WAKER.register(cx.waker());
+        // a scancode may have arrived before the waker was registered
+        match QUEUE.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The modifier keys held down when a key was pressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    lalt: bool,
+    ralt: bool,
+}
+
+impl Held {
+    fn update(&mut self, code: KeyCode, down: bool) {
+        match code {
+            KeyCode::ShiftLeft => self.lshift = down,
+            KeyCode::ShiftRight => self.rshift = down,
+            KeyCode::ControlLeft => self.lctrl = down,
+            KeyCode::ControlRight => self.rctrl = down,
+            KeyCode::AltLeft => self.lalt = down,
+            KeyCode::AltRight => self.ralt = down,
+            _ => (),
+        }
+    }
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.lshift || self.rshift,
+            ctrl: self.lctrl || self.rctrl,
+            alt: self.lalt || self.ralt,
+        }
+    }
+}
+
+/// A decoded key press
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub code: KeyCode,
+    pub decoded: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+/// Decoded key presses built on the `ScancodeStream`
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    held: Held,
+    /// `dropped()` the last time we looked
+    seen_dropped: usize,
+}
+
+impl KeyStream {
+    /// Take the keyboard, this panics if the
+    /// `ScancodeStream` has already been taken
+    pub fn new() -> Self {
+        Self {
+            scancodes: ScancodeStream::new(),
+            keyboard: Self::decoder(),
+            held: Held::default(),
+            seen_dropped: 0,
+        }
+    }
+
+    fn decoder() -> Keyboard<layouts::Us104Key, ScancodeSet1> {
+        Keyboard::new(
+            layouts::Us104Key,
+            ScancodeSet1,
+            HandleControl::MapLettersToUnicode,
+        )
+    }
+
+    /// The next key press if one is waiting
+    pub fn try_next(&mut self) -> Option<Key> {
+        while let Some(scancode) = self.scancodes.try_next() {
+            if let Some(key) = self.decode(scancode) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    fn decode(&mut self, scancode: u8) -> Option<Key> {
+        let dropped = dropped();
+        if dropped != self.seen_dropped {
+            // whatever multi-byte sequence or key we were in the
+            // middle of may have lost bytes, start over
+            crate::serial_println!(
+                "keyboard queue overflowed, {} scancodes dropped",
+                dropped - self.seen_dropped
+            );
+            self.seen_dropped = dropped;
+            self.keyboard = Self::decoder();
+            self.held = Held::default();
+        }
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let code = event.code;
+        self.held.update(code, event.state == KeyState::Down);
+        let decoded = self.keyboard.process_keyevent(event)?;
+        Some(Key {
+            code,
+            decoded,
+            modifiers: self.held.modifiers(),
+        })
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = Key;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Key>> {
+        loop {
+            let scancode = match Pin::new(&mut self.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(key) = self.decode(scancode) {
+                return Poll::Ready(Some(key));
+            }
+        }
+    }
+}
+
+/// Echo every key press to the screen, meant
+/// to be spawned as a task
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::new();
+    while let Some(key) = keys.next().await {
+        match key.decoded {
+            DecodedKey::Unicode(ch) => crate::print!("{}", ch),
+            DecodedKey::RawKey(code) => crate::print!("{:?}", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    #[kern_test]
+    fn test_queue_order() {
+        let queue = ScancodeQueue::new();
+        assert_eq!(queue.pop(), None);
+        for i in 0..10 {
+            assert!(queue.push(i));
+        }
+        for i in 0..10 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[kern_test]
+    fn test_queue_full() {
+        let queue = ScancodeQueue::new();
+        for i in 0..QUEUE_SIZE - 1 {
+            assert!(queue.push(i as u8));
+        }
+        assert!(!queue.push(0xaa));
+        assert_eq!(queue.pop(), Some(0));
+        assert!(queue.push(0xaa));
+    }
+}
@@ -7,25 +7,46 @@
 #![feature(abi_x86_interrupt)]
 #![feature(const_in_array_repeat_expressions)]
 #![feature(const_fn)]
+#![feature(wake_trait)]
+#![feature(global_asm)]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod elf;
 pub mod error;
 pub mod gdt;
 pub mod interupt;
+pub mod keyboard;
 pub mod memory;
+pub mod rtc;
 pub mod serial;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod timer;
+pub mod user;
 pub mod vga_buffer;
 
-use core::panic::PanicInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static TESTING: AtomicBool = AtomicBool::new(false);
 
 /// Initialize the kernel
 /// for normal operation
 pub fn init() {
     gdt::init();
+    syscall::init();
     interupt::init_idt();
     interupt::init_pics();
+    timer::init();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -57,7 +78,14 @@ fn test_handler(info: &PanicInfo) -> ! {
     test_panic(info)
 }
 
+/// If we are currently running under
+/// the test runner
+pub fn is_testing() -> bool {
+    TESTING.load(Ordering::Relaxed)
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
+    TESTING.store(true, Ordering::Relaxed);
     serial_println!("Running {} tests", tests.len());
     for f in tests {
         f()
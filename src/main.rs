@@ -6,7 +6,10 @@
 extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use os::println;
+use os::{
+    println,
+    task::{executor::Executor, Task},
+};
 
 use alloc::{boxed::Box, rc::Rc};
 
@@ -15,13 +18,19 @@ entry_point!(rmain);
 #[cfg(not(test))]
 fn rmain(boot_info: &'static BootInfo) -> ! {
     os::init();
-    println!("Hello, World!");
+    println!("Hello, World! It is {}", os::time::now());
     let offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
-    let mut m = unsafe { os::memory::init(offset) };
-    let mut frame_allocator = unsafe {
-        os::memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let m = unsafe { os::memory::init(offset) };
+    let frame_allocator = unsafe {
+        os::memory::BootInfoFrameAllocator::init(&boot_info.memory_map, offset)
     };
-    os::allocator::init_heap(&mut m, &mut frame_allocator).expect("failed to create heap");
+    os::allocator::init_heap(m, frame_allocator).expect("failed to create heap");
+    os::gdt::init_stacks().expect("failed to map the IST stacks");
+    os::vga_buffer::set_scrollback(os::vga_buffer::DEFAULT_SCROLLBACK);
+    os::thread::init();
+    if let Err(e) = os::interupt::apic::init() {
+        println!("APIC unavailable, staying on the PIC: {}", e);
+    }
     let x = Box::new(41);
     let y = Rc::new(100);
     {
@@ -30,7 +39,9 @@ fn rmain(boot_info: &'static BootInfo) -> ! {
     }
     println!("ref count 2: {}", Rc::strong_count(&y));
     println!("It did not crash!: {:?}", x);
-    os::hlt_loop()
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(os::keyboard::print_keypresses()));
+    executor.run()
 }
 
 #[cfg(not(test))]
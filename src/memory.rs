@@ -1,50 +1,269 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::error::Error;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB, UnusedPhysFrame, PageTable, OffsetPageTable},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PhysFrame, Size2MiB, Size4KiB,
+        UnusedPhysFrame, PageTable, OffsetPageTable, PageTableFlags,
+    },
     PhysAddr, VirtAddr,
 };
 
+mod address_space;
+pub mod stack;
+pub use address_space::AddressSpace;
+pub use stack::{Stack, StackName};
+
+const FRAME_SIZE: u64 = 4096;
+/// The size of a huge page and frame
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+const FRAMES_PER_HUGE: usize = (HUGE_PAGE_SIZE / FRAME_SIZE) as usize;
+/// Marks the end of the free list
+const FREE_LIST_END: u64 = core::u64::MAX;
+
+/// A physical frame allocator seeded from the bootloader's
+/// memory map.
+///
+/// Frames come from one of two places, first a free list of
+/// frames that have been handed back through `deallocate_frame`
+/// and then a cursor that walks forward through the usable
+/// regions of the memory map. Both are O(1) per frame.
+///
+/// 2 MiB frames only come from the cursor, the frames it skips
+/// to get to a 2 MiB boundary go on the free list. A freed
+/// 2 MiB frame is split up onto the free list as well
+///
+/// The free list is intrusive, each free frame holds the physical
+/// address of the next free frame in its first 8 bytes, which
+/// we reach through the bootloader's physical memory mapping
 pub struct BootInfoFrameAllocator {
     map: &'static MemoryMap,
-    next: usize,
+    offset: VirtAddr,
+    /// The index of the memory region the cursor is in
+    region: usize,
+    /// The next never allocated address in `region`
+    next: u64,
+    /// The head of the free list
+    free: u64,
+    total: usize,
+    used: usize,
 }
 
 impl BootInfoFrameAllocator {
-    pub unsafe fn init(map: &'static MemoryMap) -> Self {
-        Self { map, next: 0 }
-    }
-
-    fn usable_frames(&self) -> impl Iterator<Item = UnusedPhysFrame> {
-        self.map
+    /// Create a new frame allocator from the bootloader's memory map,
+    /// `offset` must be the virtual address where all of physical memory
+    /// is mapped.
+    ///
+    /// This is unsafe because the caller needs to guarantee that
+    /// every region marked `Usable` is actually unused and that only
+    /// one of these is ever created
+    pub unsafe fn init(map: &'static MemoryMap, offset: VirtAddr) -> Self {
+        let total = map
             .iter()
-            // capture only usable regions of memory
             .filter(|r| r.region_type == MemoryRegionType::Usable)
-            // get the range of their start and end
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            // convert those ranges to page starts only
-            .flat_map(|r| r.step_by(4096))
-            // convert that start to a physical frame
-            .map(|a| PhysFrame::containing_address(PhysAddr::new(a)))
-            // convert the physical frame to an unused physical frame
-            // since the first step here filters out all the reserved
-            // or used frames
-            .map(|f| unsafe { UnusedPhysFrame::new(f) })
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / FRAME_SIZE) as usize)
+            .sum();
+        Self {
+            map,
+            offset,
+            region: 0,
+            next: 0,
+            free: FREE_LIST_END,
+            total,
+            used: 0,
+        }
+    }
+    /// The number of usable frames the bootloader reported
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+    /// The number of frames currently handed out
+    pub fn used_frames(&self) -> usize {
+        self.used
+    }
+    /// The number of frames still available
+    pub fn free_frames(&self) -> usize {
+        self.total - self.used
+    }
+
+    /// Take a 2 MiB frame, counted as 512 used frames
+    pub fn allocate_huge_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        let frame = self.huge_from_map()?;
+        self.used += FRAMES_PER_HUGE;
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+
+    /// Hand back a 2 MiB frame, its 4 KiB frames can be reused
+    /// but it won't be handed out as a 2 MiB frame again
+    pub fn deallocate_huge_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        let start = frame.start_address();
+        for i in 0..FRAMES_PER_HUGE as u64 {
+            self.push_free(PhysFrame::containing_address(start + i * FRAME_SIZE));
+        }
+        self.used -= FRAMES_PER_HUGE;
+    }
+
+    fn push_free(&mut self, frame: PhysFrame) {
+        unsafe {
+            self.link(frame).write(self.free);
+        }
+        self.free = frame.start_address().as_u64();
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        if self.free == FREE_LIST_END {
+            return None;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.free));
+        self.free = unsafe { self.link(frame).read() };
+        Some(frame)
+    }
+
+    fn next_from_map(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                self.next = self.next.max(region.range.start_addr());
+                if self.next + FRAME_SIZE <= region.range.end_addr() {
+                    let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                    self.next += FRAME_SIZE;
+                    return Some(frame);
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    fn huge_from_map(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        while let Some(region) = self.map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                self.next = self.next.max(region.range.start_addr());
+                let start = align_up(self.next, HUGE_PAGE_SIZE);
+                let end = region.range.end_addr();
+                let fits = start + HUGE_PAGE_SIZE <= end;
+                // whatever is skipped is still free
+                let skip_to = if fits { start } else { end };
+                while self.next + FRAME_SIZE <= skip_to {
+                    self.push_free(PhysFrame::containing_address(PhysAddr::new(self.next)));
+                    self.next += FRAME_SIZE;
+                }
+                if fits {
+                    self.next = start + HUGE_PAGE_SIZE;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    /// The free list link stored at the start of `frame`
+    unsafe fn link(&self, frame: PhysFrame) -> *mut u64 {
+        (self.offset + frame.start_address().as_u64()).as_mut_ptr()
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let ret = self.usable_frames().nth(self.next);
-        self.next += 1;
-        ret
+        let frame = self.pop_free().or_else(|| self.next_from_map())?;
+        self.used += 1;
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        self.push_free(*frame);
+        self.used -= 1;
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// The kernel's page table and frame allocator, once
+/// they have been handed over with `install`
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frames: BootInfoFrameAllocator,
+}
+
+static KERNEL: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Hand the kernel's page table and frame allocator over
+/// so anything that needs to map memory later on can reach them
+pub fn install(mapper: OffsetPageTable<'static>, mut frames: BootInfoFrameAllocator) {
+    unsafe { fill_kernel_entries(&mut frames) };
+    *KERNEL.lock() = Some(KernelMemory { mapper, frames });
+}
+
+/// Give every empty kernel entry of the kernel's level 4 table
+/// a level 3 table. An `AddressSpace` copies the kernel entries
+/// when it is made, with all of them filled in anything the kernel
+/// maps later lands in tables every address space shares
+unsafe fn fill_kernel_entries(frames: &mut BootInfoFrameAllocator) {
+    let offset = VirtAddr::new(PHYSICAL_OFFSET.load(Ordering::Relaxed));
+    let l4 = get_table_mut(&kernel_table(), offset);
+    for (i, entry) in l4.iter_mut().enumerate() {
+        if !entry.is_unused() || address_space::user_entry(i) {
+            continue;
+        }
+        let frame = frames
+            .allocate_frame()
+            .expect("out of frames for the kernel's page tables");
+        get_table_mut(&frame, offset).zero();
+        entry.set_frame(*frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
     }
 }
 
+/// Run `f` with the kernel's page table and frame allocator,
+/// `None` if they haven't been installed yet.
+///
+/// The heap grows through here, so `f` should avoid
+/// allocating or a growing heap will find this locked
+pub fn with_kernel<T>(f: impl FnOnce(&mut KernelMemory) -> T) -> Option<T> {
+    KERNEL.lock().as_mut().map(f)
+}
+
+/// Like `with_kernel` but gives up with `None` instead
+/// of waiting if someone else is already using it
+pub fn try_with_kernel<T>(f: impl FnOnce(&mut KernelMemory) -> T) -> Option<T> {
+    KERNEL.try_lock()?.as_mut().map(f)
+}
+
+/// Where all of physical memory is mapped, 0 until `init`
+static PHYSICAL_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// The physical address of the level 4 table the bootloader
+/// left us in, the kernel's own address space
+static KERNEL_L4: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+    PHYSICAL_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
+    KERNEL_L4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    enable_no_execute();
     let l4 = active_level_4_table(offset);
     OffsetPageTable::new(l4, offset)
 }
 
+/// The kernel's level 4 table, the one active when `init` ran
+pub fn kernel_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_L4.load(Ordering::Relaxed)))
+}
+
+/// Let `PageTableFlags::NO_EXECUTE` be used, without this
+/// the bit is reserved and using it faults
+unsafe fn enable_no_execute() {
+    use x86_64::registers::model_specific::Msr;
+    const IA32_EFER: u32 = 0xc000_0080;
+    const EFER_NXE: u64 = 1 << 11;
+    let mut efer = Msr::new(IA32_EFER);
+    let value = efer.read();
+    efer.write(value | EFER_NXE);
+}
+
 unsafe fn active_level_4_table(offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
     let (frame, _) = Cr3::read();
@@ -55,26 +274,184 @@ pub unsafe fn translate(addr: VirtAddr, offset: VirtAddr) -> Option<PhysAddr> {
     translate_inner(addr, offset)
 }
 
+/// The virtual address `addr` can be reached at through
+/// the physical memory mapping, `None` before `init`
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    let offset = PHYSICAL_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return None;
+    }
+    Some(VirtAddr::new(offset + addr.as_u64()))
+}
+
+/// Where `map_mmio` starts handing out virtual addresses
+pub const MMIO_START: u64 = 0x_6666_0000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Map the device memory at `addr..addr + size` uncached
+/// and return the virtual address of `addr`
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, Error> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::<Size4KiB>::containing_address(addr + (size.max(1) - 1));
+    let frames = PhysFrame::range_inclusive(first, last);
+    let len = (last.start_address() - first.start_address()) + FRAME_SIZE;
+    let start = VirtAddr::new(MMIO_NEXT.fetch_add(len, Ordering::SeqCst));
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+    with_kernel(|k| -> Result<(), Error> {
+        for (i, frame) in frames.enumerate() {
+            let page = Page::containing_address(start + i as u64 * FRAME_SIZE);
+            // device memory is never handed out by the frame
+            // allocator so nothing else can be using it
+            let frame = unsafe { UnusedPhysFrame::new(frame) };
+            k.mapper.map_to(page, frame, flags, &mut k.frames)?.flush();
+        }
+        Ok(())
+    })
+    .ok_or(Error::MemoryNotInstalled)??;
+    Ok(start + (addr - first.start_address()))
+}
+
+/// Map `size` bytes of fresh memory at `start` with 2 MiB pages,
+/// for large regions where 4 KiB pages would waste TLB entries.
+/// `start` and `size` have to be 2 MiB aligned
+pub fn map_huge(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), Error> {
+    let pages = huge_pages(start, size)?;
+    let flags = flags | PageTableFlags::PRESENT;
+    with_kernel(|k| -> Result<(), Error> {
+        for page in pages {
+            let frame = k.frames.allocate_huge_frame().ok_or(Error::OutOfFrames)?;
+            k.mapper.map_to(page, frame, flags, &mut k.frames)?.flush();
+        }
+        Ok(())
+    })
+    .ok_or(Error::MemoryNotInstalled)?
+}
+
+/// Map the physical memory at `addr..addr + size` to `start`
+/// with 2 MiB pages, for device memory like a framebuffer that
+/// the frame allocator never hands out. Everything has to be
+/// 2 MiB aligned
+pub fn map_huge_to(
+    start: VirtAddr,
+    addr: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), Error> {
+    if !addr.is_aligned(HUGE_PAGE_SIZE) {
+        return Err(Error::NotAligned);
+    }
+    let pages = huge_pages(start, size)?;
+    let flags = flags | PageTableFlags::PRESENT;
+    with_kernel(|k| -> Result<(), Error> {
+        for (i, page) in pages.enumerate() {
+            let frame = PhysFrame::containing_address(addr + i as u64 * HUGE_PAGE_SIZE);
+            let frame = unsafe { UnusedPhysFrame::new(frame) };
+            k.mapper.map_to(page, frame, flags, &mut k.frames)?.flush();
+        }
+        Ok(())
+    })
+    .ok_or(Error::MemoryNotInstalled)?
+}
+
+fn huge_pages(start: VirtAddr, size: u64) -> Result<impl Iterator<Item = Page<Size2MiB>>, Error> {
+    if !start.is_aligned(HUGE_PAGE_SIZE) || size % HUGE_PAGE_SIZE != 0 {
+        return Err(Error::NotAligned);
+    }
+    let first = Page::containing_address(start);
+    Ok((0..size / HUGE_PAGE_SIZE).map(move |i| first + i))
+}
+
+/// Map `size` bytes of zeroed memory at `start` that ring 3
+/// can reach, see `user::map`
+pub fn map_user(start: VirtAddr, size: u64, writable: bool) -> Result<(), Error> {
+    use x86_64::{registers::control::Cr3, structures::paging::PageTableFlags as Flags};
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size.max(1) - 1));
+    let mut flags = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+    if writable {
+        flags |= Flags::WRITABLE;
+    }
+    let offset = VirtAddr::new(PHYSICAL_OFFSET.load(Ordering::Relaxed));
+    with_kernel(|k| -> Result<(), Error> {
+        for page in Page::range_inclusive(first, last) {
+            let frame = k.frames.allocate_frame().ok_or(Error::OutOfFrames)?;
+            let virt = offset + frame.start_address().as_u64();
+            k.mapper.map_to(page, frame, flags, &mut k.frames)?.flush();
+            unsafe {
+                core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, FRAME_SIZE as usize);
+                allow_user(Cr3::read().0, page.start_address());
+            }
+        }
+        Ok(())
+    })
+    .ok_or(Error::MemoryNotInstalled)?
+}
+
+/// Mark the page tables above `addr` in the table `l4` user
+/// accessible, the CPU checks the flag at every level of the walk
+pub(crate) unsafe fn allow_user(l4: PhysFrame, addr: VirtAddr) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    let offset = VirtAddr::new(PHYSICAL_OFFSET.load(Ordering::Relaxed));
+    let mut frame = l4;
+    for &idx in &[addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let table = get_table_mut(&frame, offset);
+        let entry = &mut table[idx];
+        entry.set_flags(entry.flags() | Flags::USER_ACCESSIBLE);
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+    }
+    x86_64::instructions::tlb::flush(addr);
+}
+
+/// If `addr` is currently mapped in the active page table,
+/// always false before `init`
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = PHYSICAL_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return false;
+    }
+    translate_inner(addr, VirtAddr::new(offset)).is_some()
+}
+
 pub fn translate_inner(addr: VirtAddr, offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::{registers::control::Cr3, structures::paging::page_table::FrameError};
+    use x86_64::registers::control::Cr3;
     let (l4, _) = Cr3::read();
+    translate_in(l4, addr, offset)
+}
+
+/// Translate `addr` through the level 4 table `l4`, following
+/// 1 GiB and 2 MiB pages as well as 4 KiB ones
+pub fn translate_in(l4: PhysFrame, addr: VirtAddr, offset: VirtAddr) -> Option<PhysAddr> {
     let table_indexes = [
         addr.p4_index(),
         addr.p3_index(),
         addr.p2_index(),
         addr.p1_index(),
     ];
+    // how much a single entry maps at each level
+    let sizes = [512 << 30, 1 << 30, HUGE_PAGE_SIZE, FRAME_SIZE];
     let mut frame = l4;
-    for &idx in &table_indexes {
+    for (level, &idx) in table_indexes.iter().enumerate() {
         let table = unsafe { get_table(&frame, offset) };
         let entry = &table[idx];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("Huge frames not supported"),
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // the huge page bit is the PAT bit in a level 1
+        // entry and reserved in a level 4 one
+        let last = level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if last {
+            let size: u64 = sizes[level];
+            let base = entry.addr().as_u64() & !(size - 1);
+            return Some(PhysAddr::new(base + (addr.as_u64() & (size - 1))));
         }
+        frame = PhysFrame::containing_address(entry.addr());
     }
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    unreachable!("the level 1 entry ends the walk");
 }
 
 pub unsafe fn get_table_mut(frame: &PhysFrame, offset: VirtAddr) -> &'static mut PageTable {
@@ -0,0 +1,244 @@
+//! Page tables for user programs.
+//!
+//! Every `AddressSpace` has its own level 4 table. The entries
+//! covering user memory (`USER_START..USER_END`) belong to it
+//! alone, every other entry is copied from the kernel's table
+//! so the kernel is mapped the same way in all of them. The
+//! kernel's entries are all filled in by `memory::install`
+//! and never change after that, so kernel mappings made after
+//! an address space was created show up in it too
+use super::{get_table, get_table_mut, kernel_table, phys_to_virt, with_kernel, KernelMemory};
+use crate::{
+    error::Error,
+    user::{USER_END, USER_START},
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags as Flags, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+pub struct AddressSpace {
+    l4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// An address space with nothing mapped in user memory
+    pub fn new() -> Result<Self, Error> {
+        with_kernel(|k| -> Result<Self, Error> {
+            let frame = k.frames.allocate_frame().ok_or(Error::OutOfFrames)?;
+            let offset = offset();
+            unsafe {
+                let kernel = get_table(&kernel_table(), offset);
+                let table = get_table_mut(&frame, offset);
+                table.zero();
+                for (i, entry) in kernel.iter().enumerate() {
+                    if !user_entry(i) {
+                        table[i] = entry.clone();
+                    }
+                }
+            }
+            Ok(Self { l4: *frame })
+        })
+        .ok_or(Error::MemoryNotInstalled)?
+    }
+
+    /// The frame holding the level 4 table, what `Cr3` points
+    /// at while this address space is active
+    pub fn l4(&self) -> PhysFrame {
+        self.l4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// Load this address space into `Cr3`, see
+    /// `thread::set_address_space` to keep it
+    /// active across context switches
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.l4, flags);
+    }
+
+    /// Map `size` bytes of zeroed memory at `start`, `PRESENT`
+    /// and `USER_ACCESSIBLE` are always added to `flags`.
+    /// Pages that are already mapped are left alone
+    pub fn map(&mut self, start: VirtAddr, size: u64, flags: Flags) -> Result<(), Error> {
+        let pages = user_pages(start, size)?;
+        let flags = flags | Flags::PRESENT | Flags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let l4 = self.l4;
+        with_kernel(|k| -> Result<(), Error> {
+            let mut table = unsafe { self.table() };
+            for page in pages {
+                if table.translate_page(page).is_ok() {
+                    continue;
+                }
+                let frame = k.frames.allocate_frame().ok_or(Error::OutOfFrames)?;
+                let virt = offset() + frame.start_address().as_u64();
+                unsafe {
+                    core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize);
+                }
+                let flush = table.map_to(page, frame, flags, &mut k.frames)?;
+                unsafe { super::allow_user(l4, page.start_address()) };
+                flush_if(flush, active);
+            }
+            Ok(())
+        })
+        .ok_or(Error::MemoryNotInstalled)?
+    }
+
+    /// Unmap `size` bytes at `start` and free their frames
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), Error> {
+        let pages = user_pages(start, size)?;
+        let active = self.is_active();
+        with_kernel(|k| -> Result<(), Error> {
+            let mut table = unsafe { self.table() };
+            for page in pages {
+                let (frame, flush) = table
+                    .unmap(page)
+                    .map_err(|_| Error::PageNotMapped(page.start_address()))?;
+                flush_if(flush, active);
+                unsafe { k.frames.deallocate_frame(UnusedPhysFrame::new(frame)) };
+            }
+            Ok(())
+        })
+        .ok_or(Error::MemoryNotInstalled)?
+    }
+
+    /// Change the flags of `size` bytes at `start`, `PRESENT`
+    /// and `USER_ACCESSIBLE` are always added to `flags`
+    pub fn protect(&mut self, start: VirtAddr, size: u64, flags: Flags) -> Result<(), Error> {
+        let pages = user_pages(start, size)?;
+        let flags = flags | Flags::PRESENT | Flags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let mut table = unsafe { self.table() };
+        for page in pages {
+            let flush = table
+                .update_flags(page, flags)
+                .map_err(|_| Error::PageNotMapped(page.start_address()))?;
+            flush_if(flush, active);
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` to `addr`, whatever the page flags say,
+    /// every page has to be mapped already
+    pub fn write(&mut self, addr: VirtAddr, mut bytes: &[u8]) -> Result<(), Error> {
+        let table = unsafe { self.table() };
+        let mut addr = addr;
+        while !bytes.is_empty() {
+            let page = Page::<Size4KiB>::containing_address(addr);
+            let frame = table
+                .translate_page(page)
+                .map_err(|_| Error::PageNotMapped(page.start_address()))?;
+            let in_page = addr - page.start_address();
+            let len = bytes.len().min((PAGE_SIZE - in_page) as usize);
+            let dest = offset() + (frame.start_address() + in_page).as_u64();
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest.as_mut_ptr(), len);
+            }
+            bytes = &bytes[len..];
+            addr += len as u64;
+        }
+        Ok(())
+    }
+
+    /// The physical address `addr` maps to
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        super::translate_in(self.l4, addr, offset())
+    }
+
+    unsafe fn table(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(get_table_mut(&self.l4, offset()), offset())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let l4 = self.l4;
+        with_kernel(|k| unsafe { free_user(k, l4) });
+    }
+}
+
+fn offset() -> VirtAddr {
+    phys_to_virt(PhysAddr::new(0)).expect("memory::init has not been called")
+}
+
+fn flush_if(flush: x86_64::structures::paging::mapper::MapperFlush<Size4KiB>, active: bool) {
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore();
+    }
+}
+
+/// The pages covering `start..start + size`
+/// if they are all in user memory
+fn user_pages(
+    start: VirtAddr,
+    size: u64,
+) -> Result<impl Iterator<Item = Page<Size4KiB>>, Error> {
+    let end = start
+        .as_u64()
+        .checked_add(size.max(1))
+        .ok_or(Error::NotUserMemory)?;
+    if start.as_u64() < USER_START || end > USER_END {
+        return Err(Error::NotUserMemory);
+    }
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    Ok(Page::range_inclusive(first, last))
+}
+
+/// If level 4 entry `i` covers user memory
+pub(super) fn user_entry(i: usize) -> bool {
+    let first = (USER_START >> 39) as usize;
+    let last = ((USER_END - 1) >> 39) as usize;
+    i >= first && i <= last
+}
+
+/// Free every page and page table in the user part of `l4`,
+/// then `l4` itself
+unsafe fn free_user(k: &mut KernelMemory, l4: PhysFrame) {
+    let offset = offset();
+    let free = |k: &mut KernelMemory, frame: PhysFrame| {
+        k.frames.deallocate_frame(UnusedPhysFrame::new(frame));
+    };
+    let table: &mut PageTable = get_table_mut(&l4, offset);
+    for i in (0..512).filter(|&i| user_entry(i)) {
+        let l3 = match table[i].frame() {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        for l3e in get_table(&l3, offset).iter() {
+            let l2 = match l3e.frame() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            for l2e in get_table(&l2, offset).iter() {
+                let l1 = match l2e.frame() {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                for l1e in get_table(&l1, offset).iter() {
+                    if let Ok(frame) = l1e.frame() {
+                        free(k, frame);
+                    }
+                }
+                free(k, l1);
+            }
+            free(k, l2);
+        }
+        free(k, l3);
+        table[i].set_unused();
+    }
+    free(k, l4);
+}
@@ -0,0 +1,220 @@
+//! Kernel stacks with guard pages.
+//!
+//! Every `Stack` gets its own slot of virtual memory starting at
+//! `STACKS_START`, with the lowest page of the slot left unmapped.
+//! Running off the bottom of a stack faults on that page instead
+//! of scribbling over whatever comes next, and `overflowed` turns
+//! the faulting address back into the name of the stack.
+//!
+//! Slots are reused by stacks of the same size once
+//! they are dropped, so threads coming and going don't
+//! use up the address space
+use super::{with_kernel, FRAME_SIZE};
+use crate::{
+    error::Error,
+    interupt::page_fault::{self, PageFault, Resolution},
+    sync::IrqSpinlock,
+    thread::ThreadId,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags as Flags, Size4KiB,
+        UnusedPhysFrame,
+    },
+    VirtAddr,
+};
+
+/// Where stacks are mapped, `memory::install` gives this level
+/// 4 slot its table so every `AddressSpace` has the stacks
+pub const STACKS_START: u64 = 0x_7777_0000_0000;
+/// One past the last address a stack can use
+pub const STACKS_END: u64 = 0x_7778_0000_0000;
+/// The most stacks, live or waiting to be reused
+pub const MAX_STACKS: usize = 128;
+
+static SLOTS: IrqSpinlock<[Option<Slot>; MAX_STACKS]> = IrqSpinlock::new([None; MAX_STACKS]);
+/// The next address a new slot starts at
+static NEXT: AtomicU64 = AtomicU64::new(STACKS_START);
+/// If the stack region has been claimed from `page_fault`
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// What a stack is used for, shown when it overflows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackName {
+    /// The interrupt stack table entry with this index
+    Ist(u16),
+    /// The stack interrupts and syscalls from ring 3 run on
+    Kernel,
+    Thread(ThreadId),
+}
+
+impl fmt::Display for StackName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ist(index) => write!(f, "IST{}", index),
+            Self::Kernel => write!(f, "ring 0"),
+            Self::Thread(id) => write!(f, "thread {}", id.as_u64()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    /// The start of the guard page
+    guard: VirtAddr,
+    /// The size without the guard page
+    size: u64,
+    /// `None` while the slot is free
+    name: Option<StackName>,
+}
+
+impl Slot {
+    fn bottom(&self) -> VirtAddr {
+        self.guard + FRAME_SIZE
+    }
+}
+
+/// A kernel stack with an unmapped guard page below it
+pub struct Stack {
+    slot: usize,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// Map a stack of at least `size` bytes
+    pub fn new(name: StackName, size: usize) -> Result<Self, Error> {
+        claim_region()?;
+        let size = (size as u64 + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let (slot, bottom) = reserve(name, size)?;
+        let stack = Self {
+            slot,
+            bottom,
+            top: bottom + size,
+        };
+        // dropping the stack unmaps whatever did get mapped
+        stack.map()?;
+        Ok(stack)
+    }
+
+    /// The address just past the stack, where the
+    /// stack pointer starts
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// The lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// The start of the unmapped page below the stack
+    pub fn guard(&self) -> VirtAddr {
+        self.bottom - FRAME_SIZE
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let first = Page::containing_address(self.bottom);
+        let last = Page::containing_address(self.top - 1u64);
+        Page::range_inclusive(first, last)
+    }
+
+    fn map(&self) -> Result<(), Error> {
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+        with_kernel(|k| -> Result<(), Error> {
+            for page in self.pages() {
+                let frame = k.frames.allocate_frame().ok_or(Error::OutOfFrames)?;
+                k.mapper.map_to(page, frame, flags, &mut k.frames)?.flush();
+            }
+            Ok(())
+        })
+        .ok_or(Error::MemoryNotInstalled)?
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        with_kernel(|k| {
+            for page in self.pages() {
+                if let Ok((frame, flush)) = k.mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { k.frames.deallocate_frame(UnusedPhysFrame::new(frame)) };
+                }
+            }
+        });
+        if let Some(slot) = SLOTS.lock()[self.slot].as_mut() {
+            slot.name = None;
+        }
+    }
+}
+
+/// The stack whose guard page `addr` is in, for telling a stack
+/// overflow apart from other faults. `None` if the stacks are
+/// locked, which only happens while one is being created
+pub fn overflowed(addr: VirtAddr) -> Option<StackName> {
+    let slots = SLOTS.try_lock()?;
+    slots
+        .iter()
+        .flatten()
+        .find(|s| addr >= s.guard && addr < s.bottom())
+        .and_then(|s| s.name)
+}
+
+/// Find a free slot of `size` bytes or make a new one,
+/// returns its index and the bottom of the stack
+fn reserve(name: StackName, size: u64) -> Result<(usize, VirtAddr), Error> {
+    let mut slots = SLOTS.lock();
+    let reuse = slots
+        .iter()
+        .position(|s| matches!(s, Some(s) if s.name.is_none() && s.size == size));
+    if let Some(i) = reuse {
+        let slot = slots[i].as_mut().unwrap();
+        slot.name = Some(name);
+        return Ok((i, slot.bottom()));
+    }
+    let i = slots
+        .iter()
+        .position(Option::is_none)
+        .ok_or(Error::TooManyStacks)?;
+    let guard = NEXT.fetch_add(size + FRAME_SIZE, Ordering::Relaxed);
+    if guard + size + FRAME_SIZE > STACKS_END {
+        return Err(Error::TooManyStacks);
+    }
+    let slot = Slot {
+        guard: VirtAddr::new(guard),
+        size,
+        name: Some(name),
+    };
+    slots[i] = Some(slot);
+    Ok((i, slot.bottom()))
+}
+
+/// Have faults in the stack region come to `guard_fault`
+fn claim_region() -> Result<(), Error> {
+    if CLAIMED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let claimed = page_fault::claim(
+        VirtAddr::new(STACKS_START),
+        VirtAddr::new(STACKS_END),
+        guard_fault,
+    );
+    if claimed.is_err() {
+        CLAIMED.store(false, Ordering::SeqCst);
+    }
+    claimed.map(|_| ())
+}
+
+/// A fault on a guard page can only be an overflow, the page
+/// fault handler has its own IST stack so it runs even though
+/// the stack that overflowed is unusable
+fn guard_fault(fault: &PageFault) -> Resolution {
+    if let Some(name) = overflowed(fault.addr) {
+        panic!("EXCEPTION PAGE FAULT: stack overflow in {}\n{}", name, fault);
+    }
+    Resolution::Unhandled
+}
@@ -0,0 +1,191 @@
+//! The CMOS real-time clock
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Setting this bit on the index keeps NMIs
+/// disabled while we talk to the CMOS
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_D: u8 = 0x0d;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const HOUR_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+const PM: u8 = 0x80;
+
+/// A reading from the RTC, always 24 hour and binary
+/// no matter how the clock itself is configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// The registers exactly as the clock reported them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        index.write(NMI_DISABLE | reg);
+        data.read()
+    }
+}
+
+/// Point the index back at a harmless register with the
+/// NMI bit clear, otherwise NMIs stay masked after a read
+fn enable_nmi() {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    unsafe {
+        index.write(STATUS_D);
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw() -> Raw {
+    while update_in_progress() {}
+    Raw {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: read_register(CENTURY),
+    }
+}
+
+/// Read the current time from the RTC.
+///
+/// The clock can tick over while we are reading it, so
+/// keep reading until two readings in a row agree
+pub fn read() -> RtcTime {
+    let mut last = read_raw();
+    loop {
+        let next = read_raw();
+        if next == last {
+            break;
+        }
+        last = next;
+    }
+    let status = read_register(STATUS_B);
+    enable_nmi();
+    decode(last, status)
+}
+
+fn bcd_to_binary(v: u8) -> u8 {
+    (v & 0x0f) + (v >> 4) * 10
+}
+
+fn decode(raw: Raw, status_b: u8) -> RtcTime {
+    let binary = status_b & BINARY != 0;
+    let convert = |v: u8| if binary { v } else { bcd_to_binary(v) };
+    let pm = raw.hour & PM != 0;
+    let mut hour = convert(raw.hour & !PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 hour clocks count 12, 1, 2, ... 11
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match convert(raw.century) {
+        // not every machine has a century register
+        c @ 19..=99 => u16::from(c),
+        _ => 20,
+    };
+    RtcTime {
+        year: century * 100 + u16::from(convert(raw.year)),
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    fn raw(hour: u8) -> Raw {
+        Raw {
+            second: 0x59,
+            minute: 0x30,
+            hour,
+            day: 0x28,
+            month: 0x02,
+            year: 0x24,
+            century: 0x20,
+        }
+    }
+
+    #[kern_test]
+    fn test_decode_bcd_24_hour() {
+        let t = decode(raw(0x23), HOUR_24);
+        assert_eq!(
+            t,
+            RtcTime {
+                year: 2024,
+                month: 2,
+                day: 28,
+                hour: 23,
+                minute: 30,
+                second: 59,
+            }
+        );
+    }
+
+    #[kern_test]
+    fn test_decode_bcd_12_hour() {
+        assert_eq!(decode(raw(0x12), 0).hour, 0);
+        assert_eq!(decode(raw(PM | 0x12), 0).hour, 12);
+        assert_eq!(decode(raw(PM | 0x11), 0).hour, 23);
+    }
+
+    #[kern_test]
+    fn test_decode_binary() {
+        let r = Raw {
+            second: 59,
+            minute: 30,
+            hour: PM | 3,
+            day: 28,
+            month: 2,
+            year: 24,
+            century: 0,
+        };
+        let t = decode(r, BINARY);
+        assert_eq!(t.year, 2024);
+        assert_eq!(t.hour, 15);
+        assert_eq!(t.second, 59);
+    }
+}
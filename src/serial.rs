@@ -1,24 +1,21 @@
+use crate::sync::IrqSpinlock;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSpinlock<SerialPort> = {
         let mut p = unsafe { SerialPort::new(0x3f8) };
         p.init();
-        Mutex::new(p)
+        IrqSpinlock::new(p)
     };
 }
 
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts::without_interrupts;
-    without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to Serial1 failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to Serial1 failed");
 }
 
 #[macro_export]
@@ -0,0 +1,22 @@
+//! Locks that are safe to take from interrupt handlers,
+//! and ones that park the running thread instead of spinning.
+//!
+//! `IrqSpinlock` is for data interrupt handlers touch, it
+//! disables interrupts while held so a handler can never
+//! find it locked by the code it interrupted. Everything
+//! else here blocks through a `WaitQueue` and must not be
+//! used from an interrupt handler.
+//!
+//! In debug builds taking a lock its holder can never
+//! release panics and reports the holder instead of hanging
+mod condvar;
+mod mutex;
+mod semaphore;
+mod spinlock;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{IrqSpinlock, IrqSpinlockGuard};
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,49 @@
+use super::{MutexGuard, WaitQueue};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Park a thread until another thread changes
+/// the data behind a `Mutex`
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Release the lock and park until notified, then take
+    /// the lock again. Wake ups can be spurious
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // nothing can notify between the unlock and
+        // parking with interrupts disabled
+        without_interrupts(|| {
+            drop(guard);
+            self.waiters.wait();
+        });
+        mutex.lock()
+    }
+
+    /// Wait until `cond` returns false
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
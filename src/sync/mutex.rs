@@ -0,0 +1,188 @@
+use super::WaitQueue;
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// `Mutex::owner` while nobody holds the lock
+const UNLOCKED: u64 = 0;
+
+/// A lock that parks the thread waiting for it,
+/// it can't be used from interrupt handlers
+pub struct Mutex<T: ?Sized> {
+    /// The id of the thread holding the lock plus one, or
+    /// `UNLOCKED`. Taking the lock and recording who took it
+    /// is one write so the owner is never stale
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicU64::new(UNLOCKED),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<T> {
+        #[cfg(debug_assertions)]
+        deadlock::start_waiting(&self.owner, core::any::type_name::<T>());
+        let mut guard = None;
+        self.waiters.wait_until(|| {
+            guard = self.try_lock();
+            guard.is_some()
+        });
+        #[cfg(debug_assertions)]
+        deadlock::stop_waiting();
+        guard.unwrap()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.owner
+            .compare_exchange(UNLOCKED, owner_id(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != UNLOCKED
+    }
+}
+
+/// What `Mutex::owner` holds while the running thread has the lock
+fn owner_id() -> u64 {
+    crate::thread::current().as_u64() + 1
+}
+
+/// Catching locks that can never be taken. Every thread blocked
+/// in `Mutex::lock` records the lock it is waiting on, so the
+/// chain of holders can be followed from any lock. If it comes
+/// back to the thread about to wait none of them can ever run
+#[cfg(debug_assertions)]
+mod deadlock {
+    use super::{owner_id, UNLOCKED};
+    use crate::{sync::IrqSpinlock, thread::MAX_THREADS};
+    use core::{
+        fmt,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    #[derive(Clone, Copy)]
+    struct Waiting {
+        /// The waiting thread, as stored in `Mutex::owner`
+        thread: u64,
+        /// The address of the `Mutex::owner` it is waiting on, that
+        /// mutex can't go away while a thread is waiting on it
+        owner: usize,
+    }
+
+    static WAITING: IrqSpinlock<[Option<Waiting>; MAX_THREADS]> =
+        IrqSpinlock::new([None; MAX_THREADS]);
+
+    /// Record that the running thread is about to wait on the lock
+    /// with `owner`, panicking if that lock can never be released
+    pub fn start_waiting(owner: &AtomicU64, name: &str) {
+        let me = owner_id();
+        let mut waiting = WAITING.lock();
+        // the table is locked with interrupts off, so
+        // nothing in the chain can change while we follow it
+        let mut chain = [UNLOCKED; MAX_THREADS];
+        let mut len = 0;
+        let mut holder = owner.load(Ordering::Relaxed);
+        while holder != UNLOCKED && len < MAX_THREADS {
+            chain[len] = holder;
+            len += 1;
+            if holder == me {
+                drop(waiting);
+                report(name, &chain[..len]);
+            }
+            holder = match waiting.iter().flatten().find(|w| w.thread == holder) {
+                Some(w) => unsafe { &*(w.owner as *const AtomicU64) }.load(Ordering::Relaxed),
+                None => break,
+            };
+        }
+        // every thread waits on at most one lock
+        if let Some(slot) = waiting.iter_mut().find(|w| w.is_none()) {
+            *slot = Some(Waiting {
+                thread: me,
+                owner: owner as *const AtomicU64 as usize,
+            });
+        }
+    }
+
+    /// The running thread got the lock it was waiting on
+    pub fn stop_waiting() {
+        let me = owner_id();
+        let mut waiting = WAITING.lock();
+        if let Some(slot) = waiting
+            .iter_mut()
+            .find(|w| matches!(w, Some(w) if w.thread == me))
+        {
+            *slot = None;
+        }
+    }
+
+    /// `chain` is the holder of the lock and then the holder of each
+    /// lock the one before is waiting on, ending with this thread
+    fn report(name: &str, chain: &[u64]) -> ! {
+        struct Waits<'a>(&'a [u64]);
+        impl fmt::Display for Waits<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                for holder in self.0 {
+                    write!(f, ", which is waiting on thread {}", holder - 1)?;
+                }
+                Ok(())
+            }
+        }
+        if chain.len() == 1 {
+            panic!(
+                "deadlock: Mutex<{}> is already held by thread {}",
+                name,
+                chain[0] - 1
+            );
+        }
+        panic!(
+            "deadlock: Mutex<{}> is held by thread {}{}",
+            name,
+            chain[0] - 1,
+            Waits(&chain[1..])
+        );
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.owner.store(UNLOCKED, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -0,0 +1,52 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore, `acquire` parks the
+/// thread until a permit is available
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, waiting for one to be released if
+    /// there are none left
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Take a permit if one is available
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::SeqCst);
+        while permits > 0 {
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => permits = actual,
+            }
+        }
+        false
+    }
+
+    /// Give a permit back, this is safe to
+    /// call from an interrupt handler
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,138 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// The spins in debug builds before a lock is
+/// reported as deadlocked
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: usize = 1 << 24;
+
+/// A spinlock that disables interrupts while it is held
+pub struct IrqSpinlock<T: ?Sized> {
+    locked: AtomicBool,
+    /// The thread holding the lock
+    #[cfg(debug_assertions)]
+    holder: core::sync::atomic::AtomicU64,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    /// If interrupts were on before the lock was taken
+    enable: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            holder: core::sync::atomic::AtomicU64::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        let mut spins = 0;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            #[cfg(debug_assertions)]
+            {
+                spins += 1;
+                if spins == DEADLOCK_SPINS {
+                    self.deadlock();
+                }
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+        self.acquired(enable)
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(self.acquired(enable))
+        } else {
+            if enable {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Release the lock no matter who holds it, this is
+    /// only for reporting fatal errors
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn acquired(&self, enable: bool) -> IrqSpinlockGuard<T> {
+        #[cfg(debug_assertions)]
+        self.holder
+            .store(crate::thread::current().as_u64(), Ordering::Relaxed);
+        IrqSpinlockGuard { lock: self, enable }
+    }
+
+    #[cfg(debug_assertions)]
+    #[cold]
+    fn deadlock(&self) -> ! {
+        let holder = self.holder.load(Ordering::Relaxed);
+        // whatever reports the panic may need this lock
+        unsafe { self.force_unlock() };
+        panic!(
+            "deadlock: IrqSpinlock<{}> is held by thread {}, taken again by thread {}",
+            core::any::type_name::<T>(),
+            holder,
+            crate::thread::current().as_u64()
+        );
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.enable {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::thread;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Threads parked until something wakes them, woken
+/// in the order they started waiting.
+///
+/// Waking is safe from an interrupt handler,
+/// waiting is not
+pub struct WaitQueue {
+    /// The number of threads waiting, this also gives the
+    /// queue a size so its address is unique
+    waiting: AtomicUsize,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Park until woken, this can return early so
+    /// callers should check what they are waiting for
+    pub fn wait(&self) {
+        without_interrupts(|| {
+            self.waiting.fetch_add(1, Ordering::SeqCst);
+            thread::wait(self.key());
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+
+    /// Park until `cond` returns true, `cond` runs with
+    /// interrupts disabled so nothing can wake the queue
+    /// between it returning false and the thread parking
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        without_interrupts(|| {
+            while !cond() {
+                self.wait();
+            }
+        })
+    }
+
+    /// Wake the thread that has been waiting the longest,
+    /// returns false if none were waiting
+    pub fn wake_one(&self) -> bool {
+        self.waiting.load(Ordering::SeqCst) != 0 && thread::wake(self.key(), false) != 0
+    }
+
+    /// Wake every waiting thread, returning how many there were
+    pub fn wake_all(&self) -> usize {
+        if self.waiting.load(Ordering::SeqCst) == 0 {
+            return 0;
+        }
+        thread::wake(self.key(), true)
+    }
+}
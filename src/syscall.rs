@@ -0,0 +1,192 @@
+//! The `syscall` entry point and the table of system calls.
+//!
+//! User code puts the call number in `rax` and up to six
+//! arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`
+//! the same as Linux, the result comes back in `rax`.
+//! Everything but `rcx` and `r11` is preserved.
+//!
+//! Syscalls run with interrupts disabled on the
+//! stack set with `gdt::set_kernel_stack`
+use crate::{gdt, memory, user};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{registers::model_specific::Msr, VirtAddr};
+
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+/// System call extensions, enables `syscall`/`sysret`
+const EFER_SCE: u64 = 1;
+/// RFLAGS bits cleared on entry: TF, IF and DF
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10);
+
+/// Returned for an unknown syscall number
+pub const ENOSYS: u64 = core::u64::MAX;
+/// Returned when a pointer argument isn't user memory
+pub const EFAULT: u64 = core::u64::MAX - 1;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_UPTIME_MS: u64 = 3;
+
+pub type Handler = fn(&SyscallArgs) -> u64;
+
+/// Indexed by syscall number
+const TABLE: &[Handler] = &[sys_exit, sys_write, sys_yield, sys_uptime_ms];
+
+/// The registers saved by `__syscall_entry`, in the
+/// order they are on the stack
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+/// A syscall's number and arguments
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallArgs {
+    pub number: u64,
+    pub args: [u64; 6],
+    /// Where the syscall will return to
+    pub rip: VirtAddr,
+}
+
+/// Top of the kernel stack syscalls switch to, read by `__syscall_entry`
+#[no_mangle]
+static __syscall_kernel_rsp: AtomicU64 = AtomicU64::new(0);
+/// Scratch space for the user stack pointer during the switch
+#[no_mangle]
+static __syscall_user_rsp: AtomicU64 = AtomicU64::new(0);
+
+/// Turn on `syscall`/`sysret` and point them at
+/// `__syscall_entry`, after `gdt::init`
+pub fn init() {
+    extern "C" {
+        fn __syscall_entry();
+    }
+    let sel = gdt::selectors();
+    // sysret loads SS from base + 8 and CS from base + 16,
+    // base is the kernel data segment so those are the user ones
+    let sysret_base = u64::from(sel.kernel_data.0);
+    let syscall_base = u64::from(sel.kernel_code.0);
+    set_stack(gdt::kernel_stack());
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SCE);
+        Msr::new(IA32_STAR).write((sysret_base << 48) | (syscall_base << 32));
+        Msr::new(IA32_LSTAR).write(__syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(FMASK);
+    }
+}
+
+/// Change the stack syscalls run on, `gdt::set_kernel_stack`
+/// keeps this in step with the TSS
+pub(crate) fn set_stack(top: VirtAddr) {
+    __syscall_kernel_rsp.store(top.as_u64(), Ordering::SeqCst);
+}
+
+/// Called by `__syscall_entry` with interrupts disabled
+#[no_mangle]
+extern "C" fn __syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = SyscallArgs {
+        number: frame.rax,
+        args: [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9],
+        rip: VirtAddr::new(frame.rip),
+    };
+    frame.rax = match TABLE.get(args.number as usize) {
+        Some(handler) => handler(&args),
+        None => ENOSYS,
+    };
+}
+
+/// `exit(code)`, go back to whoever called `user::run`
+fn sys_exit(args: &SyscallArgs) -> u64 {
+    unsafe { user::exit(args.args[0]) }
+}
+
+/// `write(ptr, len)`, print a string to the screen
+/// and serial port, returns the bytes written
+fn sys_write(args: &SyscallArgs) -> u64 {
+    let (ptr, len) = (args.args[0], args.args[1]);
+    let bytes = match user_slice(ptr, len) {
+        Some(bytes) => bytes,
+        None => return EFAULT,
+    };
+    let s = match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    };
+    crate::print!("{}", s);
+    crate::serial_print!("{}", s);
+    s.len() as u64
+}
+
+/// `yield()`, let other kernel threads run
+fn sys_yield(_args: &SyscallArgs) -> u64 {
+    crate::thread::yield_now();
+    0
+}
+
+/// `uptime_ms()`
+fn sys_uptime_ms(_args: &SyscallArgs) -> u64 {
+    crate::timer::uptime().as_millis() as u64
+}
+
+/// `ptr..ptr + len` as a slice if it is all mapped user memory
+fn user_slice(ptr: u64, len: u64) -> Option<&'static [u8]> {
+    let end = ptr.checked_add(len)?;
+    if ptr < user::USER_START || end > user::USER_END {
+        return None;
+    }
+    let mut page = ptr & !0xfff;
+    while page < end {
+        if !memory::is_mapped(VirtAddr::new(page)) {
+            return None;
+        }
+        page += 0x1000;
+    }
+    Some(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+global_asm!(
+    r#"
+.global __syscall_entry
+__syscall_entry:
+    movq %rsp, __syscall_user_rsp(%rip)
+    movq __syscall_kernel_rsp(%rip), %rsp
+    pushq __syscall_user_rsp(%rip)
+    pushq %rcx
+    pushq %r11
+    pushq %rax
+    pushq %rdi
+    pushq %rsi
+    pushq %rdx
+    pushq %r10
+    pushq %r8
+    pushq %r9
+    movq %rsp, %rdi
+    call __syscall_dispatch
+    popq %r9
+    popq %r8
+    popq %r10
+    popq %rdx
+    popq %rsi
+    popq %rdi
+    popq %rax
+    popq %r11
+    popq %rcx
+    popq %rsp
+    sysretq
+"#
+);
@@ -0,0 +1,52 @@
+//! Cooperative multitasking with async/await.
+//!
+//! A `Task` wraps a pinned future, executors poll them until
+//! they finish. Interrupt driven sources (the keyboard stream,
+//! `sleep_ms`) wake tasks with their `Waker` rather than
+//! the executor polling everything in a loop
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+pub mod executor;
+pub mod simple_executor;
+pub mod sleep;
+
+pub use sleep::{sleep_ms, sleep_until, Sleep};
+
+/// A unique id for every task created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
@@ -0,0 +1,194 @@
+//! An executor that only polls tasks that have been woken
+//! and halts the CPU when none are ready
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The most tasks an executor runs at once, a task is only
+/// ever queued once so this is also the most wake ups that
+/// can be waiting. Any more wait in line for a task to finish
+pub const QUEUE_SIZE: usize = 128;
+
+/// A handle that can add tasks to an `Executor`,
+/// including from inside a running task
+#[derive(Clone)]
+pub struct Spawner {
+    new: Arc<Mutex<Vec<Task>>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        interrupts::without_interrupts(|| self.new.lock().push(task));
+    }
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// Tasks that have been woken, this is pushed to from
+    /// interrupt handlers so it must not lock or allocate
+    ready: Arc<ArrayQueue<TaskId>>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+    new: Arc<Mutex<Vec<Task>>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(ArrayQueue::new(QUEUE_SIZE)),
+            wakers: BTreeMap::new(),
+            new: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Start `task`, or have it wait for a free slot
+    /// if `QUEUE_SIZE` tasks are already running
+    pub fn spawn(&mut self, task: Task) {
+        if self.tasks.len() < QUEUE_SIZE {
+            self.start(task);
+        } else {
+            interrupts::without_interrupts(|| self.new.lock().push(task));
+        }
+    }
+
+    fn start(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with the same id already spawned");
+        }
+        let waker = TaskWaker::new(id, self.ready.clone());
+        waker.wake_task();
+        self.wakers.insert(id, waker);
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            new: self.new.clone(),
+        }
+    }
+
+    /// Run tasks forever, halting whenever none are ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run tasks until every one of them has finished
+    pub fn run_until_idle(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() && !self.has_new() {
+                return;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    fn has_new(&self) -> bool {
+        interrupts::without_interrupts(|| !self.new.lock().is_empty())
+    }
+
+    /// There are new tasks and room to start them
+    fn can_start_new(&self) -> bool {
+        self.tasks.len() < QUEUE_SIZE && self.has_new()
+    }
+
+    /// Start as many new tasks as there is room
+    /// for, the rest stay in line
+    fn spawn_new(&mut self) {
+        let room = QUEUE_SIZE - self.tasks.len();
+        let new: Vec<Task> = interrupts::without_interrupts(|| {
+            let mut new = self.new.lock();
+            let count = room.min(new.len());
+            new.drain(..count).collect()
+        });
+        for task in new {
+            self.start(task);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        self.spawn_new();
+        while let Ok(id) = self.ready.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                // the task finished after this wake up was queued
+                None => continue,
+            };
+            let waker = &self.wakers[&id];
+            // cleared before polling, so a wake up
+            // from inside the poll queues it again
+            waker.queued.store(false, Ordering::SeqCst);
+            let waker = Waker::from(waker.clone());
+            let mut cx = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut cx) {
+                self.tasks.remove(&id);
+                // left queued for good, so wakers still held
+                // elsewhere can't fill the queue with a dead id
+                if let Some(waker) = self.wakers.remove(&id) {
+                    waker.queued.store(true, Ordering::SeqCst);
+                }
+            }
+            self.spawn_new();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // a wake up could arrive between checking the queue
+        // and halting, so check with interrupts off. `sti` only
+        // takes effect after the next instruction so nothing
+        // can sneak in before the `hlt`
+        interrupts::disable();
+        if self.ready.is_empty() && !self.can_start_new() {
+            interrupts::enable();
+            x86_64::instructions::hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    /// The task is in `ready` or has finished,
+    /// waking it does nothing
+    queued: AtomicBool,
+    ready: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, ready: Arc<ArrayQueue<TaskId>>) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            queued: AtomicBool::new(false),
+            ready,
+        })
+    }
+
+    /// This is called from interrupt handlers, it must not panic
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) && self.ready.push(self.id).is_err() {
+            // only live tasks are queued and there are at most
+            // `QUEUE_SIZE` of them, but if that ever breaks don't
+            // leave the task looking queued when it isn't
+            self.queued.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,47 @@
+//! An executor that polls every task in turn until they
+//! all finish, wakers do nothing so this spins the CPU
+//! while tasks are waiting
+use super::Task;
+use alloc::collections::VecDeque;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+pub struct SimpleExecutor {
+    queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        self.queue.push_back(task);
+    }
+
+    /// Poll tasks until every one of them has finished
+    pub fn run(&mut self) {
+        while let Some(mut task) = self.queue.pop_front() {
+            let waker = dummy_waker();
+            let mut cx = Context::from_waker(&waker);
+            match task.poll(&mut cx) {
+                Poll::Ready(()) => (),
+                Poll::Pending => self.queue.push_back(task),
+            }
+        }
+    }
+}
+
+fn dummy_raw_waker() -> RawWaker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+fn dummy_waker() -> Waker {
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
@@ -0,0 +1,116 @@
+//! Futures that finish after a delay, the timer interrupt
+//! wakes whichever tasks are waiting on them
+use crate::timer::Deadline;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The most sleeps that can be registered with the timer at
+/// once, past this a sleep wakes itself on every poll
+pub const MAX_SLEEPERS: usize = 32;
+
+/// Wakers are only ever dropped outside of the interrupt
+/// handler, dropping one could free memory
+static SLEEPERS: Mutex<[Option<Sleeper>; MAX_SLEEPERS]> = Mutex::new([None; MAX_SLEEPERS]);
+
+struct Sleeper {
+    deadline: Deadline,
+    waker: Waker,
+    woken: bool,
+}
+
+/// Called from the timer interrupt handler
+pub(crate) fn wake_expired() {
+    // if a task is registering right now, it will
+    // be woken on the next tick
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        for sleeper in sleepers.iter_mut().flatten() {
+            if !sleeper.woken && sleeper.deadline.expired() {
+                sleeper.woken = true;
+                sleeper.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Finish after `ms` milliseconds
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_until(Deadline::after_ms(ms))
+}
+
+/// Finish once `deadline` has passed
+pub fn sleep_until(deadline: Deadline) -> Sleep {
+    Sleep {
+        deadline,
+        slot: None,
+    }
+}
+
+/// The future returned by `sleep_ms` and `sleep_until`
+pub struct Sleep {
+    deadline: Deadline,
+    /// Where our waker is in `SLEEPERS`
+    slot: Option<usize>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Deadline {
+        self.deadline
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        let deadline = self.deadline;
+        let slot = self.slot;
+        self.slot = without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            let slot = slot.or_else(|| sleepers.iter().position(Option::is_none))?;
+            sleepers[slot] = Some(Sleeper {
+                deadline,
+                waker: waker.clone(),
+                woken: false,
+            });
+            Some(slot)
+        });
+        if self.slot.is_none() {
+            waker.wake_by_ref();
+        }
+    }
+
+    fn unregister(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            // drop the waker after the lock is released
+            let old = without_interrupts(|| SLEEPERS.lock()[slot].take());
+            drop(old);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.deadline.expired() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        self.register(cx.waker());
+        // the deadline may have passed before the waker was
+        // in place, in which case nothing will wake us
+        if self.deadline.expired() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
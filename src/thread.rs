@@ -0,0 +1,473 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack with a guard page, the
+//! timer interrupt saves the running thread's registers
+//! on its stack and switches to the next runnable thread
+//! round robin.
+//!
+//! The scheduler is an `IrqSpinlock` so it is never held
+//! when the timer fires, and nothing in the interrupt path
+//! allocates.
+//!
+//! Each thread runs in an address space, the kernel's own
+//! unless it was given one with `set_address_space`, and
+//! `Cr3` is switched along with the stack
+use crate::{
+    error::Error,
+    memory::{self, AddressSpace, Stack, StackName},
+    sync::IrqSpinlock,
+    timer::{self, Deadline},
+};
+use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame};
+
+/// The most threads, including the boot thread,
+/// that can exist at once
+pub const MAX_THREADS: usize = 64;
+/// The size of each thread's stack
+pub const STACK_SIZE: usize = 16 * 1024;
+/// Timer ticks a thread runs for before it is preempted
+pub const TIME_SLICE: u64 = 10;
+
+static SCHEDULER: IrqSpinlock<Scheduler> = IrqSpinlock::new(Scheduler::new());
+/// The id of the running thread, kept outside the scheduler
+/// so the locks in `sync` can read it without locking
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+/// Orders the threads waiting on a `WaitQueue`
+static TICKETS: AtomicU64 = AtomicU64::new(0);
+
+/// A unique id for every thread created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Sleeping(Deadline),
+    /// Waiting for another thread to finish
+    Joining(ThreadId),
+    /// Parked on the `WaitQueue` at `queue` until woken
+    Waiting { queue: usize, ticket: u64 },
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    /// The saved stack pointer while the thread isn't running
+    rsp: usize,
+    /// Only held to keep the stack alive, `None` for the boot
+    /// thread which runs on the stack the bootloader gave us
+    _stack: Option<Stack>,
+    /// Taken by the thread the first time it runs
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// `None` for the kernel's address space
+    space: Option<Arc<AddressSpace>>,
+}
+
+impl Thread {
+    /// The level 4 table to load while this thread runs
+    fn l4(&self) -> PhysFrame {
+        match &self.space {
+            Some(space) => space.l4(),
+            None => memory::kernel_table(),
+        }
+    }
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    /// The slot of the running thread
+    current: usize,
+    started: bool,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            threads: [None; MAX_THREADS],
+            current: 0,
+            started: false,
+        }
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.threads[self.current]
+            .as_mut()
+            .expect("the running thread has no slot")
+    }
+
+    fn is_finished(&self, id: ThreadId) -> bool {
+        !self
+            .threads
+            .iter()
+            .flatten()
+            .any(|t| t.id == id && t.state != State::Finished)
+    }
+
+    fn runnable(&self, slot: usize) -> bool {
+        match &self.threads[slot] {
+            Some(t) => match t.state {
+                State::Running | State::Ready => true,
+                State::Sleeping(deadline) => deadline.expired(),
+                State::Joining(id) => self.is_finished(id),
+                State::Waiting { .. } | State::Finished => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Find the next thread to run after the current one,
+    /// the current thread is only picked if nothing else can run
+    fn pick(&self) -> Option<usize> {
+        (1..=MAX_THREADS)
+            .map(|i| (self.current + i) % MAX_THREADS)
+            .find(|&slot| self.runnable(slot))
+    }
+
+    /// Make `next` the running thread, returning where to save
+    /// the current stack pointer and the stack pointer to load
+    fn switch_to(&mut self, next: usize) -> Option<(*mut usize, usize)> {
+        let current = self.current();
+        if current.state == State::Running {
+            current.state = State::Ready;
+        }
+        if next == self.current {
+            self.current().state = State::Running;
+            return None;
+        }
+        let from = &mut self.current().rsp as *mut usize;
+        self.current = next;
+        let to = self.current();
+        to.state = State::Running;
+        CURRENT.store(to.id.0, Ordering::Relaxed);
+        load_l4(to.l4());
+        Some((from, to.rsp))
+    }
+
+    /// Take every finished thread other than the current
+    /// one out of the table so it can be dropped
+    fn reap(&mut self, dead: &mut [Option<Thread>; MAX_THREADS]) {
+        let current = self.current;
+        let threads = self.threads.iter_mut().zip(dead.iter_mut());
+        for (i, (thread, dead)) in threads.enumerate() {
+            let finished = match thread {
+                Some(t) => t.state == State::Finished,
+                None => false,
+            };
+            if finished && i != current {
+                *dead = thread.take();
+            }
+        }
+    }
+}
+
+/// Turn the running code into the boot thread and
+/// start preempting it, this needs the heap
+pub fn init() {
+    let mut sched = SCHEDULER.lock();
+    if sched.started {
+        return;
+    }
+    sched.threads[0] = Some(Thread {
+        id: ThreadId(0),
+        state: State::Running,
+        rsp: 0,
+        _stack: None,
+        entry: None,
+        space: None,
+    });
+    sched.current = 0;
+    sched.started = true;
+}
+
+/// If `init` has been called
+pub fn is_started() -> bool {
+    SCHEDULER.lock().started
+}
+
+/// The id of the running thread, the boot
+/// thread is always `0`
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::Relaxed))
+}
+
+/// A handle to wait for a thread to finish
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<IrqSpinlock<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Block until the thread finishes and
+    /// take what it returned
+    pub fn join(self) -> T {
+        block(State::Joining(self.id));
+        reap();
+        let result = self.result.lock().take();
+        result.expect("thread finished without a result")
+    }
+}
+
+/// Run the current thread in `space`, or the kernel's address
+/// space for `None`, starting now. Returns the address space
+/// it was in before, which can't be dropped while active.
+/// This needs `init` to have been called
+pub fn set_address_space(space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+    let mut sched = SCHEDULER.lock();
+    assert!(sched.started, "thread::init has not been called");
+    let current = sched.current();
+    let previous = core::mem::replace(&mut current.space, space);
+    load_l4(current.l4());
+    previous
+}
+
+/// Start a new thread running `f`, in the kernel's address space
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let result = Arc::new(IrqSpinlock::new(None));
+    let out = result.clone();
+    let entry: Box<dyn FnOnce() + Send> = Box::new(move || {
+        let value = f();
+        *out.lock() = Some(value);
+    });
+    let id = ThreadId::new();
+    let stack = Stack::new(StackName::Thread(id), STACK_SIZE)?;
+    let rsp = unsafe { initial_stack(&stack) };
+    let thread = Thread {
+        id,
+        state: State::Ready,
+        rsp,
+        _stack: Some(stack),
+        entry: Some(entry),
+        space: None,
+    };
+    // a thread that didn't fit has to be dropped
+    // after the lock is released
+    let rejected = {
+        let mut sched = SCHEDULER.lock();
+        assert!(sched.started, "thread::init has not been called");
+        match sched.threads.iter().position(Option::is_none) {
+            Some(slot) => {
+                sched.threads[slot] = Some(thread);
+                None
+            }
+            None => Some(thread),
+        }
+    };
+    match rejected {
+        Some(_) => Err(Error::TooManyThreads),
+        None => Ok(JoinHandle { id, result }),
+    }
+}
+
+/// Let another thread run, if there is one ready
+pub fn yield_now() {
+    block(State::Ready);
+}
+
+/// Block the running thread for `ms` milliseconds
+pub fn sleep_ms(ms: u64) {
+    block(State::Sleeping(Deadline::after_ms(ms)));
+}
+
+/// Block the running thread until `deadline`
+pub fn sleep_until(deadline: Deadline) {
+    block(State::Sleeping(deadline));
+}
+
+/// Put the running thread in `state` and switch away until
+/// it can run again, halting if nothing is runnable
+fn block(state: State) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().current().state = state;
+        loop {
+            let next = {
+                let mut sched = SCHEDULER.lock();
+                sched.pick().map(|next| sched.switch_to(next))
+            };
+            match next {
+                Some(Some((from, to))) => {
+                    unsafe { switch(from, to) };
+                    return;
+                }
+                Some(None) => return,
+                // nothing can run, wait for the timer
+                // to wake a sleeper
+                None => {
+                    interrupts::enable();
+                    x86_64::instructions::hlt();
+                    interrupts::disable();
+                }
+            }
+        }
+    })
+}
+
+/// Park the running thread on the wait queue `queue`,
+/// see `sync::WaitQueue`. Before `init` there is nothing
+/// to switch to so this just waits for an interrupt
+pub(crate) fn wait(queue: usize) {
+    let started = SCHEDULER.lock().started;
+    if !started {
+        interrupts::without_interrupts(|| {
+            interrupts::enable();
+            x86_64::instructions::hlt();
+        });
+        return;
+    }
+    let ticket = TICKETS.fetch_add(1, Ordering::Relaxed);
+    block(State::Waiting { queue, ticket });
+}
+
+/// Make the threads waiting on `queue` ready, only the
+/// one that has waited longest unless `all` is set.
+/// Returns the number woken
+pub(crate) fn wake(queue: usize, all: bool) -> usize {
+    let mut sched = SCHEDULER.lock();
+    let waiting = |t: &Thread| match t.state {
+        State::Waiting { queue: q, ticket } if q == queue => Some(ticket),
+        _ => None,
+    };
+    if all {
+        let mut woken = 0;
+        for t in sched.threads.iter_mut().flatten() {
+            if waiting(t).is_some() {
+                t.state = State::Ready;
+                woken += 1;
+            }
+        }
+        return woken;
+    }
+    let first = sched
+        .threads
+        .iter_mut()
+        .flatten()
+        .filter(|t| waiting(t).is_some())
+        .min_by_key(|t| waiting(t));
+    match first {
+        Some(t) => {
+            t.state = State::Ready;
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Drop the stacks of threads that have finished
+fn reap() {
+    let mut dead: [Option<Thread>; MAX_THREADS] = [None; MAX_THREADS];
+    SCHEDULER.lock().reap(&mut dead);
+    drop(dead);
+}
+
+/// Called from the timer interrupt handler once the
+/// interrupt has been acknowledged
+pub(crate) fn preempt() {
+    if timer::ticks() % TIME_SLICE != 0 {
+        return;
+    }
+    let next = match SCHEDULER.try_lock() {
+        Some(mut sched) if sched.started => match sched.pick() {
+            Some(next) => sched.switch_to(next),
+            None => None,
+        },
+        _ => None,
+    };
+    if let Some((from, to)) = next {
+        unsafe { switch(from, to) };
+    }
+}
+
+/// Where every new thread starts, `switch` returns here
+/// with interrupts disabled
+extern "C" fn thread_start() -> ! {
+    let entry = SCHEDULER.lock().current().entry.take();
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    block(State::Finished);
+    unreachable!("a finished thread was scheduled");
+}
+
+/// Lay out a new stack so switching to it
+/// "returns" into `thread_start`
+unsafe fn initial_stack(stack: &Stack) -> usize {
+    let mut rsp = stack.top().align_down(16u64).as_mut_ptr::<u64>();
+    // a fake return address for `thread_start` so the
+    // stack is aligned like it was called
+    rsp = rsp.sub(1);
+    rsp.write(0);
+    rsp = rsp.sub(1);
+    rsp.write(thread_start as usize as u64);
+    // rbp, rbx, r12-r15
+    for _ in 0..6 {
+        rsp = rsp.sub(1);
+        rsp.write(0);
+    }
+    rsp as usize
+}
+
+/// Switch `Cr3` to `l4` unless it is already loaded,
+/// loading it again would needlessly flush the TLB
+fn load_l4(l4: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != l4 {
+        unsafe { Cr3::write(l4, flags) };
+    }
+}
+
+extern "C" {
+    /// Save the callee saved registers on the current stack,
+    /// store the stack pointer in `from` and resume
+    /// the thread whose stack pointer is `to`
+    fn __thread_switch(from: *mut usize, to: usize);
+}
+
+unsafe fn switch(from: *mut usize, to: usize) {
+    __thread_switch(from, to);
+}
+
+global_asm!(
+    r#"
+.global __thread_switch
+__thread_switch:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%rdi)
+    movq %rsi, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+"#
+);
@@ -0,0 +1,163 @@
+//! Wall-clock time, the RTC is read once at boot
+//! and the timer's tick count carries it forward
+use crate::{rtc, timer};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The UNIX time when `init` read the RTC
+static BOOT_UNIX: AtomicU64 = AtomicU64::new(0);
+/// The tick count when `init` read the RTC
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Read the RTC, everything else in here
+/// counts from this reading
+pub fn init() {
+    let now = DateTime::from(rtc::read());
+    BOOT_TICKS.store(timer::ticks(), Ordering::SeqCst);
+    BOOT_UNIX.store(now.to_unix(), Ordering::SeqCst);
+}
+
+/// The time since the UNIX epoch
+pub fn unix_time() -> Duration {
+    let ticks = timer::ticks() - BOOT_TICKS.load(Ordering::SeqCst);
+    let hz = u64::from(timer::HZ);
+    Duration::from_secs(BOOT_UNIX.load(Ordering::SeqCst) + ticks / hz)
+        + Duration::from_nanos((ticks % hz) * 1_000_000_000 / hz)
+}
+
+/// The current UNIX timestamp in seconds
+pub fn unix_timestamp() -> u64 {
+    unix_time().as_secs()
+}
+
+/// The current date and time in UTC
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_timestamp())
+}
+
+/// A broken down UTC date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert a UNIX timestamp, this uses Howard Hinnant's
+    /// `civil_from_days` to turn the day count into a date
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64;
+        let rem = secs % SECS_PER_DAY;
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Seconds since the UNIX epoch, the inverse of `from_unix`
+    pub fn to_unix(&self) -> u64 {
+        let (month, day) = (i64::from(self.month), i64::from(self.day));
+        let year = i64::from(self.year) - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days as u64 * SECS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl From<rtc::RtcTime> for DateTime {
+    fn from(t: rtc::RtcTime) -> Self {
+        Self {
+            year: t.year,
+            month: t.month,
+            day: t.day,
+            hour: t.hour,
+            minute: t.minute,
+            second: t.second,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[kern_test]
+    fn test_unix_epoch() {
+        assert_eq!(DateTime::from_unix(0), date(1970, 1, 1, 0, 0, 0));
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+    }
+
+    #[kern_test]
+    fn test_known_timestamps() {
+        let cases = [
+            (951_782_400, date(2000, 2, 29, 0, 0, 0)),
+            (1_709_164_799, date(2024, 2, 28, 23, 59, 59)),
+            (4_107_542_400, date(2100, 3, 1, 0, 0, 0)),
+        ];
+        for &(secs, expected) in cases.iter() {
+            assert_eq!(DateTime::from_unix(secs), expected);
+            assert_eq!(expected.to_unix(), secs);
+        }
+    }
+
+    #[kern_test]
+    fn test_round_trip() {
+        let mut secs = 0;
+        while secs < 5_000_000_000 {
+            assert_eq!(DateTime::from_unix(secs).to_unix(), secs);
+            secs += 86_399 * 37;
+        }
+    }
+}
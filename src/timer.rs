@@ -0,0 +1,200 @@
+//! A monotonic tick counter driven by the timer interrupt,
+//! along with sleeping, deadlines and timer callbacks.
+//!
+//! The PIT is programmed to `HZ` by `init`, if the APIC
+//! takes over it runs its own timer at the same rate
+use crate::error::Error;
+use core::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Timer interrupts per second
+pub const HZ: u32 = 1000;
+/// The most callbacks that can be waiting at once
+pub const MAX_CALLBACKS: usize = 32;
+const PIT_HZ: u32 = 1_193_182;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static CALLBACKS: Mutex<[Option<Callback>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+/// Bumped every time a callback is registered so a stale
+/// `TimerId` can't cancel whatever took its slot
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    generation: usize,
+    /// The tick this should run on
+    deadline: u64,
+    /// The ticks between runs for periodic callbacks
+    period: Option<u64>,
+    f: fn(),
+}
+
+/// A handle to a registered callback, pass it
+/// to `cancel` to stop it running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    generation: usize,
+}
+
+/// Program PIT channel 0 to fire at `HZ`
+pub fn init() {
+    let divisor = PIT_HZ / HZ;
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel0 = Port::<u8>::new(0x40);
+    unsafe {
+        // channel 0, lobyte/hibyte, mode 2 (rate generator)
+        command.write(0b0011_0100);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt handler
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut due: [Option<fn()>; MAX_CALLBACKS] = [None; MAX_CALLBACKS];
+    // if the table is being changed right now, whatever is
+    // due will run on the next tick
+    if let Some(mut callbacks) = CALLBACKS.try_lock() {
+        for (slot, due) in callbacks.iter_mut().zip(due.iter_mut()) {
+            if let Some(cb) = slot {
+                if cb.deadline <= now {
+                    *due = Some(cb.f);
+                    match cb.period {
+                        Some(period) => cb.deadline = now + period,
+                        None => *slot = None,
+                    }
+                }
+            }
+        }
+    }
+    for f in due.iter().flatten() {
+        f();
+    }
+    crate::task::sleep::wake_expired();
+}
+
+/// The number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// The time since the timer started
+pub fn uptime() -> Duration {
+    let ticks = ticks();
+    Duration::from_secs(ticks / u64::from(HZ))
+        + Duration::from_nanos((ticks % u64::from(HZ)) * 1_000_000_000 / u64::from(HZ))
+}
+
+fn ms_to_ticks(ms: u64) -> u64 {
+    // round up, sleeping for less than asked isn't ok
+    (ms * u64::from(HZ) + 999) / 1000
+}
+
+/// The ticks to wait for at least `ms` milliseconds to pass,
+/// the current tick is already partly over so it doesn't count
+fn ticks_for(ms: u64) -> u64 {
+    match ms_to_ticks(ms) {
+        0 => 0,
+        ticks => ticks + 1,
+    }
+}
+
+/// A point in the future measured in ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    /// The deadline `ms` milliseconds from now
+    pub fn after_ms(ms: u64) -> Self {
+        Self(ticks() + ticks_for(ms))
+    }
+    /// The deadline at an absolute tick count
+    pub fn at_tick(tick: u64) -> Self {
+        Self(tick)
+    }
+    pub fn tick(&self) -> u64 {
+        self.0
+    }
+    pub fn expired(&self) -> bool {
+        ticks() >= self.0
+    }
+    /// The time left until the deadline, zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        let left = self.0.saturating_sub(ticks());
+        Duration::from_millis(left * 1000 / u64::from(HZ))
+    }
+}
+
+/// Halt until `ms` milliseconds have passed, this needs
+/// interrupts enabled or it will never wake up
+pub fn sleep_ms(ms: u64) {
+    sleep_until(Deadline::after_ms(ms));
+}
+
+/// Halt until `deadline` has passed
+pub fn sleep_until(deadline: Deadline) {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "sleeping with interrupts disabled"
+    );
+    while !deadline.expired() {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Run `f` once, `ms` milliseconds from now.
+///
+/// Callbacks run inside the timer interrupt, they should be
+/// short and can't take any lock that is held with
+/// interrupts enabled
+pub fn after_ms(ms: u64, f: fn()) -> Result<TimerId, Error> {
+    register(ticks_for(ms).max(1), None, f)
+}
+
+/// Run `f` every `ms` milliseconds until cancelled,
+/// see `after_ms` for what `f` is allowed to do
+pub fn every_ms(ms: u64, f: fn()) -> Result<TimerId, Error> {
+    let period = ms_to_ticks(ms).max(1);
+    register(period, Some(period), f)
+}
+
+fn register(delay: u64, period: Option<u64>, f: fn()) -> Result<TimerId, Error> {
+    use x86_64::instructions::interrupts::without_interrupts;
+    without_interrupts(|| {
+        let mut callbacks = CALLBACKS.lock();
+        let slot = callbacks
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::TooManyTimers)?;
+        let generation = GENERATION.fetch_add(1, Ordering::SeqCst);
+        callbacks[slot] = Some(Callback {
+            generation,
+            deadline: ticks() + delay,
+            period,
+            f,
+        });
+        Ok(TimerId { slot, generation })
+    })
+}
+
+/// Stop a callback from running, returns false if
+/// it already ran or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    use x86_64::instructions::interrupts::without_interrupts;
+    without_interrupts(|| {
+        let mut callbacks = CALLBACKS.lock();
+        match callbacks[id.slot] {
+            Some(cb) if cb.generation == id.generation => {
+                callbacks[id.slot] = None;
+                true
+            }
+            _ => false,
+        }
+    })
+}
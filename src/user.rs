@@ -0,0 +1,98 @@
+//! Running code in ring 3.
+//!
+//! User code lives between `USER_START` and `USER_END`, it is
+//! mapped with `map` and started with `run`, which returns
+//! once the code makes the `exit` syscall. Only one user
+//! program runs at a time since they share the ring 0 stack
+use crate::{error::Error, gdt, memory};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{instructions::interrupts, VirtAddr};
+
+/// The lowest address user code can use
+pub const USER_START: u64 = 0x_1000_0000_0000;
+/// One past the highest address user code can use
+pub const USER_END: u64 = 0x_2000_0000_0000;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// The kernel stack pointer saved by `__enter_user`
+static mut KERNEL_RSP: usize = 0;
+
+/// Map `size` bytes of zeroed user memory at `start`
+pub fn map(start: VirtAddr, size: u64, writable: bool) -> Result<(), Error> {
+    let end = start.as_u64().checked_add(size).ok_or(Error::NotUserMemory)?;
+    if start.as_u64() < USER_START || end > USER_END {
+        return Err(Error::NotUserMemory);
+    }
+    memory::map_user(start, size, writable)
+}
+
+/// Jump to `entry` in ring 3 with the stack pointer at
+/// `stack` and wait for it to exit, returns the exit code.
+///
+/// Interrupts are enabled while the user code runs
+pub unsafe fn run(entry: VirtAddr, stack: VirtAddr) -> u64 {
+    extern "C" {
+        fn __enter_user(rip: u64, rsp: u64, cs: u64, ss: u64, save: *mut usize) -> u64;
+    }
+    assert!(
+        !RUNNING.swap(true, Ordering::SeqCst),
+        "a user program is already running"
+    );
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let sel = gdt::selectors();
+    let code = __enter_user(
+        entry.as_u64(),
+        stack.as_u64(),
+        u64::from(sel.user_code.0),
+        u64::from(sel.user_data.0),
+        &mut KERNEL_RSP,
+    );
+    RUNNING.store(false, Ordering::SeqCst);
+    if enabled {
+        interrupts::enable();
+    }
+    code
+}
+
+/// Abandon the user program and return `code` from `run`,
+/// this is called from the exit syscall
+pub(crate) unsafe fn exit(code: u64) -> ! {
+    extern "C" {
+        fn __exit_user(rsp: usize, code: u64) -> !;
+    }
+    assert!(RUNNING.load(Ordering::SeqCst), "no user program is running");
+    __exit_user(KERNEL_RSP, code)
+}
+
+global_asm!(
+    r#"
+.global __enter_user
+__enter_user:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%r8)
+    pushq %rcx
+    pushq %rsi
+    pushq $0x202
+    pushq %rdx
+    pushq %rdi
+    iretq
+
+.global __exit_user
+__exit_user:
+    movq %rdi, %rsp
+    movq %rsi, %rax
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+"#
+);
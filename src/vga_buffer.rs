@@ -1,15 +1,35 @@
+use crate::sync::IrqSpinlock;
+use alloc::{boxed::Box, vec};
 use core::fmt::Write;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
+mod ansi;
+
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::default());
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer::default());
 }
 
 const VGA_BUFFER_START: usize = 0xb8000;
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+/// Tab stops are every this many columns
+const TAB_WIDTH: usize = 8;
+/// The lines of history `main` keeps, see `set_scrollback`
+pub const DEFAULT_SCROLLBACK: usize = 500;
+/// How far Shift+PageUp and Shift+PageDown scroll,
+/// a screen less a line to keep some context
+pub const PAGE_LINES: usize = BUFFER_HEIGHT - 1;
+
+/// The CRT controller's index and data ports, the
+/// hardware cursor is set through these
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+/// Set in `CRTC_CURSOR_START` to hide the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
 
 #[allow(dead_code)]
 #[repr(u8)]
@@ -33,6 +53,30 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    fn from_u8(value: u8) -> Self {
+        const ALL: [Color; 16] = [
+            Color::Black,
+            Color::Blue,
+            Color::Green,
+            Color::Cyan,
+            Color::Red,
+            Color::Magenta,
+            Color::Brown,
+            Color::LightGray,
+            Color::DarkGray,
+            Color::LightBlue,
+            Color::LightGreen,
+            Color::LightCyan,
+            Color::LightRed,
+            Color::Pink,
+            Color::Yellow,
+            Color::White,
+        ];
+        ALL[usize::from(value & 0xf)]
+    }
+}
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct ColorCode(u8);
@@ -42,6 +86,12 @@ impl ColorCode {
         let back_sh = (back as u8) << 4;
         Self(back_sh | fore as u8)
     }
+    fn fore(self) -> Color {
+        Color::from_u8(self.0)
+    }
+    fn back(self) -> Color {
+        Color::from_u8(self.0 >> 4)
+    }
 }
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -50,22 +100,108 @@ struct ScreenChar {
     color: ColorCode,
 }
 
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK_ROW: Row = [ScreenChar {
+    ascii_ch: b' ',
+    color: ColorCode(0),
+}; BUFFER_WIDTH];
+
+/// The lines that have scrolled off the top of the screen.
+///
+/// Everything is allocated up front so scrolling never
+/// touches the heap, a panic can still print while the
+/// allocator is locked
+struct Scrollback {
+    /// A ring of lines, the oldest at `start`
+    lines: Box<[Row]>,
+    start: usize,
+    len: usize,
+    /// The live screen, kept while looking back through history
+    live: Box<[Row]>,
+    /// How many lines back we are looking, 0 for the live screen
+    offset: usize,
+}
+
+impl Scrollback {
+    fn new(depth: usize) -> Self {
+        Self {
+            lines: vec![BLANK_ROW; depth].into_boxed_slice(),
+            start: 0,
+            len: 0,
+            live: vec![BLANK_ROW; BUFFER_HEIGHT].into_boxed_slice(),
+            offset: 0,
+        }
+    }
+
+    fn push(&mut self, row: Row) {
+        let depth = self.lines.len();
+        if self.len < depth {
+            self.lines[(self.start + self.len) % depth] = row;
+            self.len += 1;
+        } else {
+            self.lines[self.start] = row;
+            self.start = (self.start + 1) % depth;
+        }
+    }
+
+    /// Line `i` of the history followed by the live screen
+    fn line(&self, i: usize) -> &Row {
+        if i < self.len {
+            &self.lines[(self.start + i) % self.lines.len()]
+        } else {
+            &self.live[i - self.len]
+        }
+    }
+}
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// A text console on the VGA buffer.
+///
+/// Text goes wherever the cursor is and the screen scrolls
+/// once the cursor runs off the bottom. `\n`, `\r`, `\t` and
+/// backspace move the cursor, the hardware cursor follows
+/// along after every write.
+///
+/// ANSI CSI sequences are interpreted so the same text looks
+/// alike here and on a serial terminal: SGR colors, cursor
+/// movement, erasing and showing or hiding the cursor.
+///
+/// With `set_scrollback` the lines that scroll off the top are
+/// kept and can be looked back through, any output brings the
+/// live screen back
 pub struct Writer {
+    row: usize,
     col: usize,
     color: ColorCode,
+    /// What SGR 0 goes back to, the colors from `set_color`
+    default: ColorCode,
+    /// Set by SGR 1, which makes the foreground bright, to the
+    /// foreground SGR 22 goes back to
+    bold: Option<Color>,
+    parser: ansi::Parser,
+    /// `None` until `set_scrollback`, it needs the heap
+    scrollback: Option<Scrollback>,
     buf: &'static mut Buffer,
 }
 
 impl core::default::Default for Writer {
     fn default() -> Self {
+        // start on the bottom row so whatever
+        // the bootloader printed scrolls away
+        let color = ColorCode::new(Color::Yellow, Color::Black);
         Self {
+            row: BUFFER_HEIGHT - 1,
             col: 0,
-            color: ColorCode::new(Color::Yellow, Color::Black),
+            color,
+            default: color,
+            bold: None,
+            parser: ansi::Parser::new(),
+            scrollback: None,
             buf: unsafe { &mut *(VGA_BUFFER_START as *mut Buffer) },
         }
     }
@@ -73,32 +209,189 @@ impl core::default::Default for Writer {
 
 impl Writer {
     fn write_string(&mut self, s: &str) {
+        self.show_live();
         for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+            match self.parser.advance(byte) {
+                Some(ansi::Output::Byte(byte)) => match byte {
+                    0x20..=0x7e | b'\n' | b'\r' | b'\t' | 0x08 => self.write_byte(byte),
+                    _ => self.write_byte(0xfe),
+                },
+                Some(ansi::Output::Csi(csi)) => self.csi(&csi),
+                None => {}
+            }
+        }
+        self.update_cursor();
+    }
+
+    /// Carry out a CSI sequence, unknown ones are ignored
+    fn csi(&mut self, csi: &ansi::Csi) {
+        let n = usize::from(csi.param_or(0, 1));
+        let (row, col) = self.position();
+        match csi.action {
+            b'm' if !csi.private => self.sgr(csi.params()),
+            b'A' => self.row = row.saturating_sub(n),
+            b'B' => self.row = (row + n).min(BUFFER_HEIGHT - 1),
+            b'C' => self.col = (col + n).min(BUFFER_WIDTH - 1),
+            b'D' => self.col = col.saturating_sub(n),
+            b'E' => self.set_position(row + n, 0),
+            b'F' => self.set_position(row.saturating_sub(n), 0),
+            b'G' => self.set_position(row, n - 1),
+            b'H' | b'f' => {
+                let col = usize::from(csi.param_or(1, 1));
+                self.set_position(n - 1, col - 1);
+            }
+            b'J' => self.erase_screen(csi.param_or(0, 0)),
+            b'K' => self.erase_line(csi.param_or(0, 0)),
+            b'h' | b'l' if csi.private && csi.params() == [25] => {
+                self.show_cursor(csi.action == b'h')
+            }
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition, no parameters is a reset
+    fn sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.sgr(&[0]);
+            return;
+        }
+        for &p in params {
+            let (fore, back) = (self.color.fore(), self.color.back());
+            match p {
+                0 => {
+                    self.color = self.default;
+                    self.bold = None;
+                }
+                1 => {
+                    self.bold = self.bold.or(Some(fore));
+                    self.color = ColorCode::new(Color::from_u8(fore as u8 | 8), back);
+                }
+                22 => {
+                    if let Some(normal) = self.bold.take() {
+                        self.color = ColorCode::new(normal, back);
+                    }
+                }
+                30..=37 => {
+                    let bold = self.bold.is_some();
+                    self.set_fore(ansi::color(p - 30, false), ansi::color(p - 30, bold));
+                }
+                39 => self.set_fore(self.default.fore(), self.default.fore()),
+                40..=47 => self.color = ColorCode::new(fore, ansi::color(p - 40, false)),
+                49 => self.color = ColorCode::new(fore, self.default.back()),
+                90..=97 => {
+                    let bright = ansi::color(p - 90, true);
+                    self.set_fore(bright, bright);
+                }
+                100..=107 => self.color = ColorCode::new(fore, ansi::color(p - 100, true)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Show `shown` as the foreground, `normal` is what
+    /// SGR 22 goes back to while bold is on
+    fn set_fore(&mut self, normal: Color, shown: Color) {
+        if self.bold.is_some() {
+            self.bold = Some(normal);
+        }
+        self.color = ColorCode::new(shown, self.color.back());
+    }
+
+    /// `ESC [ n J`, 0 erases from the cursor to the end of the
+    /// screen, 1 from the start to the cursor and 2 everything.
+    /// The cursor stays where it is
+    fn erase_screen(&mut self, mode: u16) {
+        let (row, _) = self.position();
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for r in row + 1..BUFFER_HEIGHT {
+                    self.clear_row(r);
+                }
+            }
+            1 => {
+                for r in 0..row {
+                    self.clear_row(r);
+                }
+                self.erase_line(1);
             }
+            2 | 3 => {
+                for r in 0..BUFFER_HEIGHT {
+                    self.clear_row(r);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `ESC [ n K`, like `erase_screen` but for the cursor's row
+    fn erase_line(&mut self, mode: u16) {
+        let (row, col) = self.position();
+        let cols = match mode {
+            0 => col..BUFFER_WIDTH,
+            1 => 0..col + 1,
+            2 => 0..BUFFER_WIDTH,
+            _ => return,
+        };
+        let blank = self.blank();
+        for c in cols {
+            self.buf.chars[row][c].write(blank);
         }
     }
     fn write_byte(&mut self, b: u8) {
         match b {
             b'\n' => self.new_line(),
-            byte => {
-                if self.col >= BUFFER_WIDTH {
-                    self.new_line();
+            b'\r' => self.col = 0,
+            b'\t' => {
+                let stop = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < stop.min(BUFFER_WIDTH) {
+                    self.put(b' ');
                 }
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.col;
-                let color = self.color;
-                self.buf.chars[row][col].write(ScreenChar {
-                    ascii_ch: byte,
-                    color,
-                });
-                self.col += 1;
             }
+            0x08 => self.backspace(),
+            byte => self.put(byte),
+        }
+    }
+
+    /// Write `byte` at the cursor and move it on,
+    /// wrapping onto the next line if needed
+    fn put(&mut self, byte: u8) {
+        if self.col >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let (row, col, color) = (self.row, self.col, self.color);
+        self.buf.chars[row][col].write(ScreenChar {
+            ascii_ch: byte,
+            color,
+        });
+        self.col += 1;
+    }
+
+    /// Erase the character before the cursor, going
+    /// back to the end of the previous row if needed
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col = self.col.min(BUFFER_WIDTH) - 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = BUFFER_WIDTH - 1;
+        } else {
+            return;
         }
+        let blank = self.blank();
+        self.buf.chars[self.row][self.col].write(blank);
     }
+
     fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < BUFFER_HEIGHT {
+            self.row += 1;
+            return;
+        }
+        if self.scrollback.is_some() {
+            let top = self.read_row(0);
+            self.scrollback.as_mut().unwrap().push(top);
+        }
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let c = self.buf.chars[row][col].read();
@@ -106,24 +399,181 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.col = 0;
     }
 
+    /// Blank the whole screen and put the cursor
+    /// in the top left corner
     pub fn clear(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        self.show_live();
+        for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
+        self.row = 0;
         self.col = 0;
+        self.update_cursor();
     }
 
     fn clear_row(&mut self, row: usize) {
-        let b = ScreenChar {
+        let b = self.blank();
+        for c in 0..BUFFER_WIDTH {
+            self.buf.chars[row][c].write(b);
+        }
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
             ascii_ch: b' ',
             color: self.color,
+        }
+    }
+
+    /// The cursor's row and column
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.col.min(BUFFER_WIDTH - 1))
+    }
+
+    /// Move the cursor, clamped to the screen
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.show_live();
+        self.row = row.min(BUFFER_HEIGHT - 1);
+        self.col = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    /// The foreground and background colors text is written in
+    pub fn color(&self) -> (Color, Color) {
+        (self.color.fore(), self.color.back())
+    }
+
+    /// Change the colors, this is also what an
+    /// ANSI reset (`ESC [ 0 m`) goes back to
+    pub fn set_color(&mut self, fore: Color, back: Color) {
+        self.color = ColorCode::new(fore, back);
+        self.default = self.color;
+        self.bold = None;
+    }
+
+    /// Run `f` with the colors changed, then put them back
+    pub fn with_color<T>(&mut self, fore: Color, back: Color, f: impl FnOnce(&mut Self) -> T) -> T {
+        let saved = (self.color, self.default, self.bold);
+        self.set_color(fore, back);
+        let result = f(self);
+        self.restore_color(saved);
+        result
+    }
+
+    fn restore_color(&mut self, (color, default, bold): (ColorCode, ColorCode, Option<Color>)) {
+        self.color = color;
+        self.default = default;
+        self.bold = bold;
+    }
+
+    pub fn show_cursor(&mut self, visible: bool) {
+        let start = crtc_read(CRTC_CURSOR_START);
+        if visible {
+            crtc_write(CRTC_CURSOR_START, start & !CURSOR_DISABLE);
+        } else {
+            crtc_write(CRTC_CURSOR_START, start | CURSOR_DISABLE);
+        }
+    }
+
+    /// How many lines back through the history the
+    /// screen is showing, 0 for the live screen
+    pub fn scrolled(&self) -> usize {
+        self.scrollback.as_ref().map_or(0, |sb| sb.offset)
+    }
+
+    /// Show the history `lines` further back, as far as it goes
+    pub fn scroll_back(&mut self, lines: usize) {
+        let sb = match self.scrollback.as_mut() {
+            Some(sb) if sb.len > 0 => sb,
+            _ => return,
         };
-        for c in 0..BUFFER_WIDTH {
-            self.buf.chars[row][c].write(b);
+        if sb.offset == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    sb.live[row][col] = self.buf.chars[row][col].read();
+                }
+            }
+        }
+        sb.offset = (sb.offset + lines).min(sb.len);
+        self.render();
+    }
+
+    /// Show the history `lines` closer to the live screen
+    pub fn scroll_forward(&mut self, lines: usize) {
+        let offset = self.scrolled();
+        if lines >= offset {
+            self.show_live();
+        } else {
+            self.scrollback.as_mut().unwrap().offset = offset - lines;
+            self.render();
+        }
+    }
+
+    /// Stop looking through the history and
+    /// put the live screen back
+    pub fn show_live(&mut self) {
+        let sb = match self.scrollback.as_mut() {
+            Some(sb) if sb.offset > 0 => sb,
+            _ => return,
+        };
+        sb.offset = 0;
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buf.chars[row][col].write(sb.live[row][col]);
+            }
+        }
+        self.update_cursor();
+    }
+
+    /// Draw the screen `offset` lines back
+    fn render(&mut self) {
+        let sb = self.scrollback.as_ref().expect("no scrollback to render");
+        let first = sb.len - sb.offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = sb.line(first + row);
+            for col in 0..BUFFER_WIDTH {
+                self.buf.chars[row][col].write(line[col]);
+            }
         }
+        self.update_cursor();
+    }
+
+    fn read_row(&self, row: usize) -> Row {
+        let mut line = BLANK_ROW;
+        for (col, c) in line.iter_mut().enumerate() {
+            *c = self.buf.chars[row][col].read();
+        }
+        line
+    }
+
+    /// Move the hardware cursor to our cursor, or off
+    /// the screen while looking through the history
+    fn update_cursor(&self) {
+        let (row, col) = self.position();
+        let pos = match self.scrolled() {
+            0 => (row * BUFFER_WIDTH + col) as u16,
+            _ => (BUFFER_HEIGHT * BUFFER_WIDTH) as u16,
+        };
+        crtc_write(CRTC_CURSOR_HIGH, (pos >> 8) as u8);
+        crtc_write(CRTC_CURSOR_LOW, pos as u8);
+    }
+}
+
+fn crtc_write(register: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
+fn crtc_read(register: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).read()
     }
 }
 
@@ -134,6 +584,58 @@ impl Write for Writer {
     }
 }
 
+/// Keep the last `lines` lines that scroll off the screen,
+/// 0 turns the history off. This needs the heap, whatever
+/// history there was is thrown away
+pub fn set_scrollback(lines: usize) {
+    let new = match lines {
+        0 => None,
+        lines => Some(Scrollback::new(lines)),
+    };
+    let old = {
+        let mut w = WRITER.lock();
+        w.show_live();
+        core::mem::replace(&mut w.scrollback, new)
+    };
+    // freed once the console is unlocked
+    drop(old);
+}
+
+/// `Writer::scroll_back` on the console
+pub fn scroll_back(lines: usize) {
+    WRITER.lock().scroll_back(lines);
+}
+
+/// `Writer::scroll_forward` on the console
+pub fn scroll_forward(lines: usize) {
+    WRITER.lock().scroll_forward(lines);
+}
+
+/// `Writer::show_live` on the console
+pub fn show_live() {
+    WRITER.lock().show_live();
+}
+
+/// Change the colors everything is printed in from now on
+pub fn set_color(fore: Color, back: Color) {
+    WRITER.lock().set_color(fore, back);
+}
+
+/// Run `f` with the colors changed, anything it prints
+/// comes out in them. Output from other threads in the
+/// meantime will too
+pub fn with_color<T>(fore: Color, back: Color, f: impl FnOnce() -> T) -> T {
+    let saved = {
+        let mut w = WRITER.lock();
+        let saved = (w.color, w.default, w.bold);
+        w.set_color(fore, back);
+        saved
+    };
+    let result = f();
+    WRITER.lock().restore_color(saved);
+    result
+}
+
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -147,10 +649,7 @@ macro_rules! print {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    use x86_64::instructions::interrupts::without_interrupts;
-    without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[cfg(test)]
@@ -176,15 +675,127 @@ mod test {
         WRITER.lock().clear();
         let s = "Some test string that fits on a single line";
         println!("{}", s);
-        check_writer_line(BUFFER_HEIGHT - 2, s);
+        check_writer_line(0, s);
+        assert_eq!(WRITER.lock().position(), (1, 0));
     }
     #[kern_test]
     fn test_print_wrap() {
         WRITER.lock().clear();
         let s = "Some text that doesn't fit on a single line, it needs to actually wrap around to the next line";
         print!("{}", s);
-        check_writer_line(BUFFER_HEIGHT - 2, &s[..80]);
-        check_writer_line(BUFFER_HEIGHT - 1, &s[80..]);
+        check_writer_line(0, &s[..80]);
+        check_writer_line(1, &s[80..]);
+    }
+
+    #[kern_test]
+    fn test_clear() {
+        print!("something");
+        WRITER.lock().clear();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let sc = WRITER.lock().buf.chars[row][col].read();
+                assert_eq!(sc.ascii_ch, b' ');
+            }
+        }
+        assert_eq!(WRITER.lock().position(), (0, 0));
+    }
+
+    #[kern_test]
+    fn test_tab_and_backspace() {
+        WRITER.lock().clear();
+        print!("ab\tc");
+        check_writer_line(0, "ab      c");
+        print!("\x08\x08d");
+        check_writer_line(0, "ab     d ");
+        print!("\rX");
+        check_writer_line(0, "Xb     d ");
+        assert_eq!(WRITER.lock().position(), (0, 1));
+    }
+
+    #[kern_test]
+    fn test_colors() {
+        WRITER.lock().clear();
+        set_color(Color::White, Color::Blue);
+        print!("a");
+        with_color(Color::Red, Color::Black, || print!("b"));
+        print!("c");
+        let w = WRITER.lock();
+        assert_eq!(w.color(), (Color::White, Color::Blue));
+        let color = |col: usize| w.buf.chars[0][col].read().color;
+        assert_eq!(color(0), ColorCode::new(Color::White, Color::Blue));
+        assert_eq!(color(1), ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(color(2), ColorCode::new(Color::White, Color::Blue));
+        drop(w);
+        set_color(Color::Yellow, Color::Black);
+    }
+
+    #[kern_test]
+    fn test_ansi_colors() {
+        WRITER.lock().clear();
+        print!("\x1b[31ma\x1b[1;44mb\x1b[0mc\x1b[92md\x1b[m");
+        let w = WRITER.lock();
+        let color = |col: usize| w.buf.chars[0][col].read().color;
+        assert_eq!(color(0), ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(color(1), ColorCode::new(Color::LightRed, Color::Blue));
+        assert_eq!(color(2), ColorCode::new(Color::Yellow, Color::Black));
+        assert_eq!(color(3), ColorCode::new(Color::LightGreen, Color::Black));
+        assert_eq!(w.color(), (Color::Yellow, Color::Black));
+        drop(w);
+        check_writer_line(0, "abcd");
+    }
+
+    #[kern_test]
+    fn test_ansi_bold_off() {
+        WRITER.lock().clear();
+        // the default foreground is already bright
+        print!("\x1b[1ma\x1b[22mb\x1b[1;32mc\x1b[22md\x1b[m");
+        let w = WRITER.lock();
+        let fore = |col: usize| w.buf.chars[0][col].read().color.fore();
+        assert_eq!(fore(0), Color::Yellow);
+        assert_eq!(fore(1), Color::Yellow);
+        assert_eq!(fore(2), Color::LightGreen);
+        assert_eq!(fore(3), Color::Green);
+    }
+
+    #[kern_test]
+    fn test_ansi_cursor() {
+        WRITER.lock().clear();
+        print!("\x1b[5;10Hx");
+        check_writer_line(4, "         x");
+        print!("\x1b[2Ay\x1b[3Dz");
+        check_writer_line(2, "        z y");
+        assert_eq!(WRITER.lock().position(), (2, 9));
+        print!("\x1b[Hw");
+        check_writer_line(0, "w");
+        print!("\x1b[20G\x1b[Bv");
+        check_writer_line(1, "                   v");
+    }
+
+    #[kern_test]
+    fn test_ansi_erase() {
+        WRITER.lock().clear();
+        println!("first line");
+        print!("second line");
+        print!("\x1b[7D\x1b[K");
+        check_writer_line(1, "seco       ");
+        print!("\x1b[2J");
+        check_writer_line(0, "          ");
+        assert_eq!(WRITER.lock().position(), (1, 4));
+        // split across writes
+        print!("\x1b[");
+        print!("31mr");
+        assert_eq!(WRITER.lock().buf.chars[1][4].read().color.fore(), Color::Red);
+        print!("\x1b[0m");
+    }
+
+    #[kern_test]
+    fn test_set_position() {
+        WRITER.lock().clear();
+        WRITER.lock().set_position(10, 5);
+        print!("here");
+        check_writer_line(10, "     here");
+        WRITER.lock().set_position(100, 100);
+        assert_eq!(WRITER.lock().position(), (BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1));
     }
 
     #[kern_test]
@@ -0,0 +1,232 @@
+//! Splitting ANSI escape sequences out of the text written
+//! to the console.
+//!
+//! Only CSI sequences (`ESC [ params final`) are recognised,
+//! any other escape is dropped. Interpreting them is up
+//! to `Writer`
+use super::Color;
+
+/// The most parameters kept, any more are ignored
+pub const MAX_PARAMS: usize = 8;
+const ESC: u8 = 0x1b;
+
+/// A complete CSI sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    /// The sequence started with `?`, like `ESC [ ? 25 h`
+    pub private: bool,
+    /// The byte that ended the sequence, says what it does
+    pub action: u8,
+}
+
+impl Csi {
+    /// The parameters, a missing one is 0
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count]
+    }
+
+    /// Parameter `i`, or `default` if it is missing or 0
+    pub fn param_or(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(&p) if p != 0 => p,
+            _ => default,
+        }
+    }
+}
+
+/// What a byte written to the console turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Plain text or a control character
+    Byte(u8),
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// Just saw `ESC`
+    Escape,
+    /// Inside `ESC [`
+    Csi,
+}
+
+/// Sequences can be split across writes, so the
+/// parser keeps its state between bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            csi: Csi {
+                params: [0; MAX_PARAMS],
+                count: 0,
+                private: false,
+                action: 0,
+            },
+        }
+    }
+
+    /// Feed in the next byte, `None` while it is
+    /// part of an unfinished escape sequence
+    pub fn advance(&mut self, byte: u8) -> Option<Output> {
+        match self.state {
+            State::Ground if byte == ESC => {
+                self.state = State::Escape;
+                None
+            }
+            State::Ground => Some(Output::Byte(byte)),
+            State::Escape if byte == b'[' => {
+                self.state = State::Csi;
+                self.csi = Self::new().csi;
+                None
+            }
+            // we only know CSI, anything else is dropped
+            // along with the byte after the escape
+            State::Escape => {
+                self.state = State::Ground;
+                None
+            }
+            State::Csi => self.csi_byte(byte),
+        }
+    }
+
+    fn csi_byte(&mut self, byte: u8) -> Option<Output> {
+        let csi = &mut self.csi;
+        match byte {
+            b'0'..=b'9' => {
+                if csi.count == 0 {
+                    csi.count = 1;
+                }
+                if let Some(p) = csi.params.get_mut(csi.count - 1) {
+                    *p = p.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                }
+                None
+            }
+            b';' => {
+                // an empty first parameter still counts
+                csi.count = (csi.count.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            b'?' => {
+                csi.private = true;
+                None
+            }
+            // the final byte
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                csi.count = csi.count.min(MAX_PARAMS);
+                csi.action = byte;
+                Some(Output::Csi(*csi))
+            }
+            // a malformed sequence, give up on it
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+/// The VGA color for ANSI color `n` (0 to 7),
+/// brightened for the 90 and 100 ranges
+pub fn color(n: u16, bright: bool) -> Color {
+    const NORMAL: [Color; 8] = [
+        Color::Black,
+        Color::Red,
+        Color::Green,
+        Color::Brown,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightGray,
+    ];
+    const BRIGHT: [Color; 8] = [
+        Color::DarkGray,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::Yellow,
+        Color::LightBlue,
+        Color::Pink,
+        Color::LightCyan,
+        Color::White,
+    ];
+    let n = usize::from(n % 8);
+    if bright {
+        BRIGHT[n]
+    } else {
+        NORMAL[n]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use kern_test::kern_test;
+
+    fn parse_one(s: &str) -> Option<Output> {
+        let mut parser = Parser::new();
+        let mut last = None;
+        for b in s.bytes() {
+            if let Some(out) = parser.advance(b) {
+                last = Some(out);
+            }
+        }
+        last
+    }
+
+    fn csi(s: &str) -> Csi {
+        match parse_one(s) {
+            Some(Output::Csi(csi)) => csi,
+            other => panic!("expected a CSI sequence got {:?}", other),
+        }
+    }
+
+    #[kern_test]
+    fn test_plain_text() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.advance(b'a'), Some(Output::Byte(b'a')));
+        assert_eq!(parser.advance(b'\n'), Some(Output::Byte(b'\n')));
+    }
+
+    #[kern_test]
+    fn test_params() {
+        let c = csi("\x1b[1;31m");
+        assert_eq!(c.params(), &[1, 31]);
+        assert_eq!(c.action, b'm');
+        assert!(!c.private);
+        let c = csi("\x1b[H");
+        assert_eq!(c.params(), &[]);
+        assert_eq!(c.param_or(0, 1), 1);
+        let c = csi("\x1b[;5H");
+        assert_eq!(c.params(), &[0, 5]);
+        assert_eq!(c.param_or(0, 1), 1);
+        assert_eq!(c.param_or(1, 1), 5);
+        let c = csi("\x1b[?25l");
+        assert!(c.private);
+        assert_eq!(c.params(), &[25]);
+    }
+
+    #[kern_test]
+    fn test_split_and_limits() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.advance(0x1b), None);
+        assert_eq!(parser.advance(b'['), None);
+        assert_eq!(parser.advance(b'2'), None);
+        assert!(matches!(parser.advance(b'J'), Some(Output::Csi(_))));
+        let c = csi("\x1b[1;2;3;4;5;6;7;8;9;10m");
+        assert_eq!(c.params().len(), MAX_PARAMS);
+        assert_eq!(csi("\x1b[99999999A").params(), &[u16::max_value()]);
+        // not CSI, both bytes are dropped
+        assert_eq!(parse_one("\x1b7"), None);
+        assert_eq!(parse_one("\x1b[\x01x"), Some(Output::Byte(b'x')));
+    }
+}
@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    error::Error,
+    memory::{self, AddressSpace},
+    serial_print, serial_println, thread, user,
+};
+use x86_64::{structures::paging::PageTableFlags as Flags, VirtAddr};
+
+/// A level 4 slot of its own, clear of `memory::MMIO_START`,
+/// `stack::STACKS_START`, `allocator::HEAP_START` and the user range
+const KERNEL_ONLY: u64 = 0x_3333_0000_0000;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    thread::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel(|k| k.frames.free_frames()).unwrap()
+}
+
+fn addr(offset: u64) -> VirtAddr {
+    VirtAddr::new(user::USER_START + offset)
+}
+
+/// Runs first, before anything has made a kernel stack, so the
+/// stack region is only in the space if `memory::install` made it
+#[kern_test]
+fn thread_stack_in_earlier_space() {
+    let space = Arc::new(AddressSpace::new().unwrap());
+    let handle = thread::spawn(move || {
+        let on_stack = [1u8, 2, 3];
+        thread::set_address_space(Some(space));
+        // the stack has to still be mapped
+        let sum: u8 = on_stack.iter().sum();
+        thread::yield_now();
+        thread::set_address_space(None);
+        sum
+    })
+    .unwrap();
+    assert_eq!(handle.join(), 6);
+}
+
+#[kern_test]
+fn map_write_translate() {
+    let mut space = AddressSpace::new().unwrap();
+    assert_eq!(space.translate(addr(0)), None);
+    space.map(addr(0), 8192, Flags::WRITABLE).unwrap();
+    assert!(space.translate(addr(0)).is_some());
+    assert!(space.translate(addr(4096)).is_some());
+    assert_eq!(space.translate(addr(8192)), None);
+    // spans the two pages
+    space.write(addr(4090), b"hello world").unwrap();
+    space.protect(addr(0), 8192, Flags::NO_EXECUTE).unwrap();
+    space.unmap(addr(4096), 4096).unwrap();
+    assert_eq!(space.translate(addr(4096)), None);
+    match space.write(addr(4096), b"x") {
+        Err(Error::PageNotMapped(page)) => assert_eq!(page, addr(4096)),
+        other => panic!("expected PageNotMapped got {:?}", other),
+    }
+}
+
+#[kern_test]
+fn rejects_kernel_memory() {
+    let mut space = AddressSpace::new().unwrap();
+    let kernel = VirtAddr::new(0x1000);
+    let end = VirtAddr::new(user::USER_END - 4096);
+    assert!(matches!(space.map(kernel, 4096, Flags::WRITABLE), Err(Error::NotUserMemory)));
+    assert!(matches!(space.unmap(kernel, 4096), Err(Error::NotUserMemory)));
+    assert!(matches!(space.map(end, 8192, Flags::WRITABLE), Err(Error::NotUserMemory)));
+}
+
+#[kern_test]
+fn frees_frames_on_drop() {
+    let before = free_frames();
+    let mut space = AddressSpace::new().unwrap();
+    space.map(addr(0), 16 * 4096, Flags::WRITABLE).unwrap();
+    space.map(addr(1 << 30), 4096, Flags::WRITABLE).unwrap();
+    assert!(free_frames() < before);
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
+
+#[kern_test]
+fn spaces_are_isolated() {
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    first.map(addr(0), 4096, Flags::WRITABLE).unwrap();
+    second.map(addr(0), 4096, Flags::WRITABLE).unwrap();
+    first.write(addr(0), &[1]).unwrap();
+    second.write(addr(0), &[2]).unwrap();
+    assert_ne!(first.translate(addr(0)), second.translate(addr(0)));
+
+    let first = Arc::new(first);
+    let previous = thread::set_address_space(Some(first.clone()));
+    assert!(first.is_active());
+    assert_eq!(unsafe { *addr(0).as_ptr::<u8>() }, 1);
+    // the kernel is still mapped
+    assert!(memory::is_mapped(VirtAddr::from_ptr(&previous)));
+    thread::set_address_space(Some(Arc::new(second)));
+    assert_eq!(unsafe { *addr(0).as_ptr::<u8>() }, 2);
+    thread::set_address_space(previous);
+    assert!(!memory::is_mapped(addr(0)));
+}
+
+#[kern_test]
+fn switched_with_threads() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(addr(0), 4096, Flags::WRITABLE).unwrap();
+    space.write(addr(0), &[42]).unwrap();
+    let space = Arc::new(space);
+    let handle = thread::spawn(move || {
+        thread::set_address_space(Some(space));
+        thread::yield_now();
+        let value = unsafe { *addr(0).as_ptr::<u8>() };
+        thread::set_address_space(None);
+        value
+    })
+    .unwrap();
+    assert_eq!(handle.join(), 42);
+    assert!(!memory::is_mapped(addr(0)));
+}
+
+#[kern_test]
+fn sees_later_kernel_mappings() {
+    let start = VirtAddr::new(KERNEL_ONLY);
+    let space = Arc::new(AddressSpace::new().unwrap());
+    memory::map_huge(start, memory::HUGE_PAGE_SIZE, Flags::WRITABLE).unwrap();
+    let ptr = start.as_mut_ptr::<u64>();
+    unsafe { ptr.write_volatile(7) };
+    let previous = thread::set_address_space(Some(space));
+    assert_eq!(unsafe { ptr.read_volatile() }, 7);
+    thread::set_address_space(previous);
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use kern_test::kern_test;
+    use os::{interupt::apic, serial_print, serial_println, timer};
+    use x86_64::{
+        instructions::{interrupts, port::Port},
+        registers::model_specific::Msr,
+    };
+
+    #[kern_test]
+    fn pic_is_masked() {
+        assert!(apic::is_enabled());
+        let master = unsafe { Port::<u8>::new(0x21).read() };
+        let slave = unsafe { Port::<u8>::new(0xa1).read() };
+        assert_eq!(master, 0xff);
+        assert_eq!(slave, 0xff);
+    }
+
+    #[kern_test]
+    fn local_apic_is_enabled() {
+        // IA32_APIC_BASE, bit 11 is the global enable
+        let base = unsafe { Msr::new(0x1b).read() };
+        assert_ne!(base & (1 << 11), 0);
+    }
+
+    #[kern_test]
+    fn timer_ticks_on_the_apic() {
+        // with the PIC masked only the
+        // APIC timer can move the ticks
+        let start = timer::ticks();
+        interrupts::enable();
+        while timer::ticks() < start + 10 {
+            x86_64::instructions::hlt();
+        }
+        interrupts::disable();
+    }
+}
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    os::interupt::apic::init().expect("QEMU has an APIC");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
@@ -0,0 +1,30 @@
+//! Helpers shared by the test binaries, each one
+//! pulls this in with `mod common;` and uses what it needs
+#![allow(dead_code)]
+use bootloader::BootInfo;
+use os::allocator::Backend;
+use x86_64::instructions::interrupts;
+
+/// Set up the kernel and its heap with `backend`,
+/// or the default backend if that is `None`
+pub fn init(info: &'static BootInfo, backend: Option<Backend>) {
+    use os::{allocator, memory::{self, BootInfoFrameAllocator}};
+    use x86_64::VirtAddr;
+    os::init();
+    let offset = VirtAddr::new(info.physical_memory_offset);
+    let mapper = unsafe { memory::init(offset) };
+    let frame_alloc = unsafe { BootInfoFrameAllocator::init(&info.memory_map, offset) };
+    match backend {
+        Some(backend) => allocator::init_heap_with(backend, mapper, frame_alloc),
+        None => allocator::init_heap(mapper, frame_alloc),
+    }
+    .expect("heap init failed");
+}
+
+/// `kern_test` runs with interrupts off, the timer
+/// and preempting threads need them on
+pub fn with_interrupts(f: impl FnOnce()) {
+    interrupts::enable();
+    f();
+    interrupts::disable();
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    elf::{self, ElfError, PF_R, PF_X, PT_LOAD},
+    error::Error,
+    serial_print, serial_println, user,
+};
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+const HEADERS: usize = 64 + 56;
+
+/// An executable with one read/execute segment holding
+/// the headers followed by `code`, which is the entry point
+fn executable(code: &[u8]) -> Vec<u8> {
+    let vaddr = user::USER_START + 0x40_0000;
+    let size = (HEADERS + code.len()) as u64;
+    let mut f = Vec::new();
+    f.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    f.resize(16, 0);
+    f.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    f.extend_from_slice(&0x3eu16.to_le_bytes()); // x86_64
+    f.extend_from_slice(&1u32.to_le_bytes());
+    f.extend_from_slice(&(vaddr + HEADERS as u64).to_le_bytes()); // entry
+    f.extend_from_slice(&64u64.to_le_bytes()); // phoff
+    f.extend_from_slice(&0u64.to_le_bytes()); // shoff
+    f.extend_from_slice(&0u32.to_le_bytes());
+    f.extend_from_slice(&64u16.to_le_bytes());
+    f.extend_from_slice(&56u16.to_le_bytes());
+    f.extend_from_slice(&1u16.to_le_bytes());
+    f.resize(64, 0);
+    f.extend_from_slice(&PT_LOAD.to_le_bytes());
+    f.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+    f.extend_from_slice(&0u64.to_le_bytes()); // offset
+    f.extend_from_slice(&vaddr.to_le_bytes());
+    f.extend_from_slice(&vaddr.to_le_bytes());
+    f.extend_from_slice(&size.to_le_bytes());
+    f.extend_from_slice(&size.to_le_bytes());
+    f.extend_from_slice(&0x1000u64.to_le_bytes());
+    f.extend_from_slice(code);
+    f
+}
+
+#[kern_test]
+fn exits_with_argc() {
+    let code = [
+        0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp]
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ];
+    let program = elf::load(&executable(&code), &["prog", "a", "b"], &["HOME=/"]).unwrap();
+    assert_eq!(program.stack.as_u64() % 16, 0);
+    assert_eq!(unsafe { program.run() }, 3);
+}
+
+#[kern_test]
+fn reads_argv() {
+    let code = [
+        0x48, 0x8b, 0x44, 0x24, 0x10, // mov rax, [rsp + 16] (argv[1])
+        0x0f, 0xb6, 0x78, 0x01, // movzx edi, byte [rax + 1]
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ];
+    let program = elf::load(&executable(&code), &["prog", "xyz"], &[]).unwrap();
+    assert_eq!(unsafe { program.run() }, u64::from(b'y'));
+}
+
+#[kern_test]
+fn programs_are_isolated() {
+    let code = [0x31, 0xff, 0x31, 0xc0, 0x0f, 0x05]; // exit(0)
+    let first = elf::load(&executable(&code), &[], &[]).unwrap();
+    // both load at the same address in their own tables
+    let second = elf::load(&executable(&code), &[], &[]).unwrap();
+    assert_eq!(unsafe { first.run() }, 0);
+    assert_eq!(unsafe { second.run() }, 0);
+    assert!(!os::memory::is_mapped(first.entry));
+}
+
+#[kern_test]
+fn rejects_malformed() {
+    let code = [0x0f, 0x05];
+    let good = executable(&code);
+    let check = |f: &[u8], want: ElfError| match elf::load(f, &[], &[]) {
+        Err(Error::Elf(e)) => assert_eq!(e, want),
+        Err(e) => panic!("expected {:?} got {:?}", want, e),
+        Ok(_) => panic!("expected {:?}", want),
+    };
+    check(&good[..40], ElfError::Truncated);
+    let mut f = good.clone();
+    f[1] = b'X';
+    check(&f, ElfError::BadMagic);
+    let mut f = good.clone();
+    f[24] ^= 0xff; // entry outside the segment
+    check(&f, ElfError::BadEntry);
+    let mut f = good.clone();
+    f[64 + 16..64 + 24].copy_from_slice(&0x1000u64.to_le_bytes()); // kernel address
+    check(&f, ElfError::SegmentOutOfRange);
+    let mut f = good;
+    f[64..68].copy_from_slice(&0u32.to_le_bytes());
+    check(&f, ElfError::NoLoadSegments);
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::memory::BootInfoFrameAllocator;
+use spin::Mutex;
+
+static FRAMES: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+#[cfg(test)]
+mod tests {
+    use super::FRAMES;
+    use os::{serial_print, serial_println};
+    use kern_test::kern_test;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    #[kern_test]
+    fn counts_frames() {
+        let mut frames = FRAMES.lock();
+        let frames = frames.as_mut().unwrap();
+        let free = frames.free_frames();
+        let used = frames.used_frames();
+        let frame = frames.allocate_frame().unwrap();
+        assert_eq!(frames.free_frames(), free - 1);
+        assert_eq!(frames.used_frames(), used + 1);
+        frames.deallocate_frame(frame);
+        assert_eq!(frames.free_frames(), free);
+        assert_eq!(frames.used_frames(), used);
+    }
+
+    #[kern_test]
+    fn reuses_freed_frames() {
+        let mut frames = FRAMES.lock();
+        let frames = frames.as_mut().unwrap();
+        let first = frames.allocate_frame().unwrap();
+        let second = frames.allocate_frame().unwrap();
+        let (first_addr, second_addr) = (first.start_address(), second.start_address());
+        assert_ne!(first_addr, second_addr);
+        frames.deallocate_frame(first);
+        frames.deallocate_frame(second);
+        assert_eq!(frames.allocate_frame().unwrap().start_address(), second_addr);
+        assert_eq!(frames.allocate_frame().unwrap().start_address(), first_addr);
+    }
+
+    #[kern_test]
+    fn many_frames() {
+        let mut frames = FRAMES.lock();
+        let frames = frames.as_mut().unwrap();
+        let mut last = None;
+        for _ in 0..10_000 {
+            let frame = frames.allocate_frame().unwrap();
+            if let Some(last) = last {
+                assert!(frame.start_address() > last);
+            }
+            last = Some(frame.start_address());
+        }
+    }
+}
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    os::init();
+    let offset = VirtAddr::new(info.physical_memory_offset);
+    *FRAMES.lock() = Some(unsafe { BootInfoFrameAllocator::init(&info.memory_map, offset) });
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
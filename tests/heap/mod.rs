@@ -0,0 +1,70 @@
+//! The heap tests every allocator backend runs, each
+//! `heap_allocations*` test binary boots with a different
+//! backend (see `common::init`) and then runs everything in here
+
+#[cfg(test)]
+mod tests {
+    use os::{allocator::assert_no_leaks, serial_print, serial_println};
+    use alloc::{boxed::Box, vec::Vec};
+    use kern_test::kern_test;
+    #[kern_test]
+    fn simple_alloc() {
+        let v = Box::new(42);
+        assert_eq!(*v, 42);
+    }
+    #[kern_test]
+    fn large_vec() {
+        let mut v = Vec::new();
+        let n = 1000;
+        for i in 0..n {
+            v.push(i);
+        }
+        assert_eq!(v.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+    #[kern_test]
+    fn reuse_mem() {
+        let _first = Box::new(-1);
+        for i in 0..os::allocator::HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    }
+    #[kern_test(leak_check)]
+    fn many_small_boxes() {
+        let n = 10_000;
+        let boxes: Vec<Box<usize>> = (0..n).map(Box::new).collect();
+        for (i, b) in boxes.iter().enumerate() {
+            assert_eq!(**b, i);
+        }
+        drop(boxes);
+        let boxes: Vec<Box<usize>> = (0..n).map(Box::new).collect();
+        assert_eq!(boxes.iter().map(|b| **b).sum::<usize>(), (n - 1) * n / 2);
+    }
+    #[kern_test(leak_check)]
+    fn counts_allocations() {
+        let before = os::allocator::stats();
+        let b = Box::new([0u8; 100]);
+        let during = os::allocator::stats();
+        assert_eq!(during.live_allocs(), before.live_allocs() + 1);
+        assert_eq!(during.live_bytes, before.live_bytes + 100);
+        drop(b);
+        let after = os::allocator::stats();
+        assert_eq!(after.frees, before.frees + 1);
+        let shown = alloc::format!("{}", after);
+        let first = alloc::format!(
+            "allocs: {} frees: {} live: {} failed: {}\n",
+            after.allocs,
+            after.frees,
+            after.live_allocs(),
+            after.failed
+        );
+        assert!(shown.starts_with(&first), "{}", shown);
+    }
+    #[kern_test]
+    fn grows_heap() {
+        let before = os::allocator::heap_size();
+        let v = alloc::vec![1u8; os::allocator::HEAP_SIZE * 4];
+        assert!(os::allocator::heap_size() > before);
+        assert_eq!(v.iter().map(|&b| b as usize).sum::<usize>(), v.len());
+    }
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use kern_test::kern_test;
+    use os::{serial_print, serial_println};
+
+    #[kern_test]
+    fn keeps_an_empty_slab() {
+        // the 1024 byte class, nothing else in here uses it
+        drop(Box::new([0u8; 1000]));
+        let before = os::allocator::stats().backend;
+        for _ in 0..100 {
+            drop(Box::new([0u8; 1000]));
+        }
+        let after = os::allocator::stats().backend;
+        assert_eq!(after.fallback_used, before.fallback_used);
+        assert_eq!(after.fallback_peak, before.fallback_peak);
+        let class = after.classes.iter().find(|c| c.block_size == 1024).unwrap();
+        assert_eq!(class.slabs, 1);
+    }
+}
+
+mod common;
+mod heap;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, Some(os::allocator::Backend::Slab));
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    serial_print!("heap_double_free... ");
+    common::init(info, Some(os::allocator::Backend::Slab));
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        // keep the slab alive so the second free
+        // finds the block's trailer
+        let _keep = alloc(layout);
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+    serial_println!("[failed]");
+    serial_println!("double free was not detected");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // the allocator is still locked from the
+    // dealloc that panicked, so nothing in here
+    // can allocate
+    serial_println!("[ok]");
+    serial_println!("{}", info);
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
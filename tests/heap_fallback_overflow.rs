@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    serial_print!("heap_fallback_overflow... ");
+    common::init(info, Some(os::allocator::Backend::Slab));
+    // too big for any slab size class
+    let layout = Layout::from_size_align(4000, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.add(layout.size()).write(0);
+        dealloc(ptr, layout);
+    }
+    serial_println!("[failed]");
+    serial_println!("overflow was not detected");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // the allocator is still locked from the
+    // dealloc that panicked, so nothing in here
+    // can allocate
+    serial_println!("[ok]");
+    serial_println!("{}", info);
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
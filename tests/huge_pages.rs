@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    error::Error,
+    memory::{self, HUGE_PAGE_SIZE},
+    serial_print, serial_println,
+};
+use x86_64::{structures::paging::PageTableFlags as Flags, PhysAddr, VirtAddr};
+
+/// Somewhere nothing else maps
+const START: u64 = 0x_5555_0000_0000;
+
+static mut OFFSET: u64 = 0;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    unsafe { OFFSET = info.physical_memory_offset };
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel(|k| k.frames.used_frames()).unwrap()
+}
+
+#[kern_test]
+fn translates_physical_memory_map() {
+    // the bootloader is free to map this with huge pages
+    let offset = unsafe { OFFSET };
+    for &phys in &[0xb8000u64, 0x20_1234, 0x40_0000] {
+        let virt = VirtAddr::new(offset + phys);
+        assert!(memory::is_mapped(virt));
+        let found = unsafe { memory::translate(virt, VirtAddr::new(offset)) };
+        assert_eq!(found, Some(PhysAddr::new(phys)));
+    }
+}
+
+#[kern_test]
+fn maps_huge_pages() {
+    let used = used_frames();
+    let start = VirtAddr::new(START);
+    memory::map_huge(start, 2 * HUGE_PAGE_SIZE, Flags::WRITABLE).unwrap();
+    // `memory::install` made the level 3 table, both pages
+    // share a level 2 table that may need making
+    let used = used_frames() - used;
+    assert!(used == 1024 || used == 1025, "{} frames used", used);
+    let first = unsafe { memory::translate(start, VirtAddr::new(OFFSET)) }.unwrap();
+    assert!(first.is_aligned(HUGE_PAGE_SIZE));
+    let inside = unsafe { memory::translate(start + 0x12345u64, VirtAddr::new(OFFSET)) };
+    assert_eq!(inside, Some(first + 0x12345u64));
+    let ptr = (start + HUGE_PAGE_SIZE + 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+}
+
+#[kern_test]
+fn maps_physical_memory() {
+    let start = VirtAddr::new(START + 0x4000_0000);
+    memory::map_huge_to(start, PhysAddr::new(0), HUGE_PAGE_SIZE, Flags::empty()).unwrap();
+    // the VGA buffer, read through both mappings
+    let ours = (start + 0xb8000u64).as_ptr::<u16>();
+    let theirs = VirtAddr::new(unsafe { OFFSET } + 0xb8000).as_ptr::<u16>();
+    assert_eq!(unsafe { ours.read_volatile() }, unsafe { theirs.read_volatile() });
+}
+
+#[kern_test]
+fn rejects_misaligned() {
+    let start = VirtAddr::new(START + 0x8000_0000);
+    let misaligned = |r: Result<(), Error>| matches!(r, Err(Error::NotAligned));
+    assert!(misaligned(memory::map_huge(start + 4096u64, HUGE_PAGE_SIZE, Flags::WRITABLE)));
+    assert!(misaligned(memory::map_huge(start, 4096, Flags::WRITABLE)));
+    let phys = PhysAddr::new(0x1000);
+    assert!(misaligned(memory::map_huge_to(start, phys, HUGE_PAGE_SIZE, Flags::empty())));
+}
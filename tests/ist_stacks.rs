@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(global_asm)]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{
+    exit_qemu,
+    gdt::{self, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, PAGE_FAULT_IST_INDEX},
+    interupt::{
+        exceptions,
+        page_fault::{self, PageFault, Resolution},
+    },
+    memory, serial_print, serial_println, QemuExitCode,
+};
+use x86_64::{
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{FrameAllocator, Mapper, Page, PageTableFlags as Flags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+/// Somewhere nothing else maps, faults here come to `on_page_fault`
+const FAULT_AT: u64 = 0x_dead_beef_0000;
+
+/// Fail unless we are running on the stack of IST entry `index`
+fn check_stack(index: u16) {
+    let local = 0u8;
+    let addr = VirtAddr::from_ptr(&local);
+    let top = gdt::ist_stack(index);
+    if addr >= top || addr < top - gdt::IST_STACK_SIZE as u64 {
+        serial_println!("[failed]");
+        serial_println!("running at {:?}, IST{} ends at {:?}", addr, index, top);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+fn on_page_fault(fault: &PageFault) -> Resolution {
+    check_stack(PAGE_FAULT_IST_INDEX);
+    let page = Page::<Size4KiB>::containing_address(fault.addr);
+    memory::with_kernel(|k| {
+        let frame = k.frames.allocate_frame().expect("out of frames");
+        let flags = Flags::PRESENT | Flags::WRITABLE;
+        k.mapper.map_to(page, frame, flags, &mut k.frames).unwrap().flush();
+    })
+    .unwrap();
+    Resolution::Resolved
+}
+
+fn on_nmi(_frame: &InterruptStackFrame) {
+    check_stack(NMI_IST_INDEX);
+}
+
+/// The machine check handler never returns, so this is the last check
+fn on_machine_check(_frame: &InterruptStackFrame) {
+    check_stack(MACHINE_CHECK_IST_INDEX);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+extern "C" {
+    fn __raise_nmi();
+    fn __raise_machine_check();
+}
+
+// a software interrupt goes through the same gate as the real thing
+global_asm!(
+    r#"
+.global __raise_nmi
+__raise_nmi:
+    int $2
+    retq
+.global __raise_machine_check
+__raise_machine_check:
+    int $18
+    retq
+"#
+);
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    serial_print!("ist_stacks... ");
+    common::init(info, None);
+    gdt::init_stacks().expect("IST stack init failed");
+
+    let start = VirtAddr::new(FAULT_AT);
+    page_fault::claim(start, start + 4096u64, on_page_fault).unwrap();
+    unsafe { start.as_ptr::<u8>().read_volatile() };
+
+    exceptions::on_nmi(Some(on_nmi));
+    unsafe { __raise_nmi() };
+
+    exceptions::on_machine_check(Some(on_machine_check));
+    unsafe { __raise_machine_check() };
+
+    panic!("Execution continued after the machine check");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
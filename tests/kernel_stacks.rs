@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    gdt,
+    memory::{self, stack, Stack, StackName},
+    serial_print, serial_println, thread,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    gdt::init_stacks().expect("IST stack init failed");
+    thread::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel(|k| k.frames.free_frames()).unwrap()
+}
+
+#[kern_test]
+fn guard_page_is_unmapped() {
+    let name = StackName::Thread(thread::current());
+    let s = Stack::new(name, 8192).unwrap();
+    assert_eq!(s.top() - s.bottom(), 8192);
+    assert!(memory::is_mapped(s.bottom()));
+    assert!(memory::is_mapped(s.top() - 1u64));
+    assert!(!memory::is_mapped(s.guard()));
+    assert_eq!(stack::overflowed(s.guard() + 100u64), Some(name));
+    assert_eq!(stack::overflowed(s.bottom()), None);
+    unsafe { (s.top() - 8u64).as_mut_ptr::<u64>().write_volatile(1) };
+}
+
+#[kern_test]
+fn ist_stack_is_guarded() {
+    let below = gdt::ist_stack(gdt::DOUBLE_FAULT_IST_INDEX) - gdt::IST_STACK_SIZE as u64 - 1u64;
+    let name = StackName::Ist(gdt::DOUBLE_FAULT_IST_INDEX);
+    assert_eq!(stack::overflowed(below), Some(name));
+}
+
+#[kern_test]
+fn kernel_stack_is_guarded() {
+    let below = gdt::kernel_stack() - gdt::KERNEL_STACK_SIZE as u64 - 1u64;
+    assert_eq!(stack::overflowed(below), Some(StackName::Kernel));
+}
+
+#[kern_test]
+fn frees_and_reuses() {
+    let name = StackName::Thread(thread::current());
+    let first = Stack::new(name, 3 * 4096).unwrap();
+    let guard = first.guard();
+    let free = free_frames();
+    drop(first);
+    assert_eq!(free_frames(), free + 3);
+    assert_eq!(stack::overflowed(guard), None);
+    let second = Stack::new(name, 3 * 4096).unwrap();
+    assert_eq!(second.guard(), guard);
+}
+
+#[kern_test]
+fn threads_run_on_guarded_stacks() {
+    let handle = thread::spawn(|| {
+        let local = 0u8;
+        stack::overflowed(VirtAddr::from_ptr(&local) - thread::STACK_SIZE as u64)
+    })
+    .unwrap();
+    let id = handle.id();
+    assert_eq!(handle.join(), Some(StackName::Thread(id)));
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{exit_qemu, serial_print, serial_println, sync::Mutex, thread, QemuExitCode};
+
+static X: Mutex<()> = Mutex::new(());
+static Y: Mutex<()> = Mutex::new(());
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    serial_print!("mutex_deadlock... ");
+    common::init(info, None);
+    thread::init();
+    let _x = X.lock();
+    thread::spawn(|| {
+        let _y = Y.lock();
+        let _x = X.lock();
+    })
+    .unwrap();
+    while !Y.is_locked() {
+        thread::yield_now();
+    }
+    // whichever of us waits last closes the
+    // cycle and should panic
+    let _y = Y.lock();
+    serial_println!("[failed]");
+    serial_println!("deadlock was not detected");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    serial_println!("{}", info);
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
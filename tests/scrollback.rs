@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    keyboard, print, println, serial_print, serial_println,
+    vga_buffer::{self, PAGE_LINES, WRITER},
+};
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+/// Check that the screen's `row` starts with `text`,
+/// reading the VGA buffer directly
+fn check_row(row: usize, text: &str) {
+    let buffer = 0xb8000 as *const u16;
+    for (col, c) in text.bytes().enumerate() {
+        let cell = unsafe { buffer.add(row * 80 + col).read_volatile() };
+        assert_eq!(cell as u8, c, "row {} is not {:?}", row, text);
+    }
+}
+
+/// Start from an empty history and a clear screen,
+/// then print `lines` numbered lines
+fn fill(depth: usize, lines: usize) {
+    vga_buffer::set_scrollback(depth);
+    WRITER.lock().clear();
+    for i in 0..lines {
+        println!("line {:02}", i);
+    }
+}
+
+#[kern_test]
+fn scrolls_through_history() {
+    // 36 lines scroll off, the screen shows 36 to 59
+    fill(100, 60);
+    check_row(0, "line 36");
+    vga_buffer::scroll_back(PAGE_LINES);
+    assert_eq!(WRITER.lock().scrolled(), PAGE_LINES);
+    check_row(0, "line 12");
+    check_row(PAGE_LINES, "line 36");
+    vga_buffer::scroll_back(100);
+    assert_eq!(WRITER.lock().scrolled(), 36);
+    check_row(0, "line 00");
+    vga_buffer::scroll_forward(PAGE_LINES);
+    check_row(0, "line 24");
+    vga_buffer::scroll_forward(100);
+    assert_eq!(WRITER.lock().scrolled(), 0);
+    check_row(0, "line 36");
+}
+
+#[kern_test]
+fn output_shows_live_screen() {
+    fill(100, 30);
+    vga_buffer::scroll_back(3);
+    check_row(0, "line 03");
+    print!("x");
+    assert_eq!(WRITER.lock().scrolled(), 0);
+    check_row(0, "line 06");
+    check_row(24, "x");
+}
+
+#[kern_test]
+fn keeps_only_depth_lines() {
+    fill(10, 50);
+    vga_buffer::scroll_back(100);
+    assert_eq!(WRITER.lock().scrolled(), 10);
+    check_row(0, "line 16");
+    vga_buffer::show_live();
+    check_row(0, "line 26");
+}
+
+#[kern_test]
+fn disabled_without_depth() {
+    fill(0, 50);
+    vga_buffer::scroll_back(PAGE_LINES);
+    assert_eq!(WRITER.lock().scrolled(), 0);
+    check_row(0, "line 26");
+}
+
+#[kern_test]
+fn scrolls_from_the_keyboard() {
+    // what the keyboard interrupt hands over, scancode set 1
+    let keys = |scancodes: &[u8]| scancodes.iter().for_each(|&s| keyboard::add_scancode(s));
+    fill(100, 60);
+    // PageUp without shift is left alone
+    keys(&[0xe0, 0x49, 0xe0, 0xc9]);
+    assert_eq!(WRITER.lock().scrolled(), 0);
+    // left shift down, PageUp twice, PageDown, shift up
+    keys(&[0x2a, 0xe0, 0x49, 0xe0, 0xc9, 0xe0, 0x49, 0xe0, 0xc9]);
+    assert_eq!(WRITER.lock().scrolled(), 36);
+    keys(&[0xe0, 0x51, 0xe0, 0xd1, 0xaa]);
+    assert_eq!(WRITER.lock().scrolled(), 36 - PAGE_LINES);
+    check_row(0, "line 24");
+    // any other key goes back to the live screen
+    keys(&[0x1e, 0x9e]);
+    assert_eq!(WRITER.lock().scrolled(), 0);
+    check_row(0, "line 36");
+}
@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use common::with_interrupts;
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{
+    serial_print, serial_println,
+    sync::{Condvar, IrqSpinlock, Mutex, Semaphore},
+    thread,
+};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    thread::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+#[kern_test]
+fn irq_spinlock_masks_interrupts() {
+    let lock = IrqSpinlock::new(0);
+    with_interrupts(|| {
+        {
+            let mut guard = lock.lock();
+            assert!(!interrupts::are_enabled());
+            assert!(lock.try_lock().is_none());
+            *guard += 1;
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    });
+}
+
+#[kern_test]
+fn mutex_counts_across_threads() {
+    static COUNT: Mutex<usize> = Mutex::new(0);
+    with_interrupts(|| {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..1000 {
+                        let mut count = COUNT.lock();
+                        let seen = *count;
+                        // give the timer a chance to preempt
+                        // us while we hold the lock
+                        thread::yield_now();
+                        *count = seen + 1;
+                    }
+                })
+                .unwrap()
+            })
+            .collect();
+        handles.into_iter().for_each(thread::JoinHandle::join);
+        assert_eq!(*COUNT.lock(), 8000);
+    });
+}
+
+#[kern_test]
+fn semaphore_limits_holders() {
+    static PERMITS: Semaphore = Semaphore::new(2);
+    static HOLDING: Mutex<(usize, usize)> = Mutex::new((0, 0));
+    with_interrupts(|| {
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                thread::spawn(|| {
+                    PERMITS.acquire();
+                    {
+                        let mut holding = HOLDING.lock();
+                        holding.0 += 1;
+                        holding.1 = holding.1.max(holding.0);
+                    }
+                    thread::sleep_ms(5);
+                    HOLDING.lock().0 -= 1;
+                    PERMITS.release();
+                })
+                .unwrap()
+            })
+            .collect();
+        handles.into_iter().for_each(thread::JoinHandle::join);
+        assert_eq!(*HOLDING.lock(), (0, 2));
+        assert_eq!(PERMITS.available(), 2);
+    });
+}
+
+#[kern_test]
+fn condvar_hands_off_items() {
+    let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+    with_interrupts(|| {
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let (items, ready) = &*queue;
+                let mut got = Vec::new();
+                while got.len() < 10 {
+                    let mut items = ready.wait_while(items.lock(), |q| q.is_empty());
+                    got.extend(items.drain(..));
+                }
+                got
+            })
+            .unwrap()
+        };
+        let (items, ready) = &*queue;
+        for i in 0..10 {
+            items.lock().push_back(i);
+            ready.notify_one();
+            thread::sleep_ms(1);
+        }
+        assert_eq!(consumer.join(), (0..10).collect::<Vec<_>>());
+    });
+}
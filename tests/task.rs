@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{rc::Rc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{cell::RefCell, panic::PanicInfo};
+use kern_test::kern_test;
+use os::{
+    serial_print, serial_println,
+    task::{executor::Executor, simple_executor::SimpleExecutor, sleep_ms, Task},
+    timer,
+};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+async fn number() -> u32 {
+    42
+}
+
+#[kern_test]
+fn simple_executor_runs_tasks() {
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    for i in 0..3 {
+        let out = out.clone();
+        executor.spawn(Task::new(async move {
+            out.borrow_mut().push(number().await + i);
+        }));
+    }
+    executor.run();
+    assert_eq!(*out.borrow(), [42, 43, 44]);
+}
+
+#[kern_test]
+fn sleeps_wake_in_order() {
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &ms in &[30, 10, 20] {
+        let out = out.clone();
+        executor.spawn(Task::new(async move {
+            sleep_ms(ms).await;
+            out.borrow_mut().push(ms);
+        }));
+    }
+    let start = timer::ticks();
+    executor.run_until_idle();
+    interrupts::disable();
+    assert_eq!(*out.borrow(), [10, 20, 30]);
+    assert!(timer::ticks() - start >= 30);
+}
+
+#[kern_test]
+fn spawner_adds_tasks() {
+    let out = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let inner = out.clone();
+    executor.spawn(Task::new(async move {
+        sleep_ms(5).await;
+        let out = inner.clone();
+        spawner.spawn(Task::new(async move {
+            *out.borrow_mut() += 1;
+        }));
+        *inner.borrow_mut() += 1;
+    }));
+    executor.run_until_idle();
+    interrupts::disable();
+    assert_eq!(*out.borrow(), 2);
+}
+
+#[kern_test]
+fn repeated_wakes_queue_once() {
+    use core::{future::Future, pin::Pin, task::{Context, Poll}};
+    /// Wakes itself more times than the queue has room
+    /// for, then finishes on the next poll
+    struct Noisy(u32);
+    impl Future for Noisy {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            self.0 += 1;
+            if self.0 > 1 {
+                return Poll::Ready(());
+            }
+            for _ in 0..4 * os::task::executor::QUEUE_SIZE {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(Noisy(0)));
+    executor.run_until_idle();
+    interrupts::disable();
+}
+
+#[kern_test]
+fn extra_tasks_wait_their_turn() {
+    let count = os::task::executor::QUEUE_SIZE + 10;
+    let done = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..count {
+        let done = done.clone();
+        executor.spawn(Task::new(async move {
+            sleep_ms(1).await;
+            *done.borrow_mut() += 1;
+        }));
+    }
+    executor.run_until_idle();
+    interrupts::disable();
+    assert_eq!(*done.borrow(), count);
+}
+
+#[kern_test]
+fn finished_tasks_ignore_wakes() {
+    use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+    /// Keeps its waker after finishing
+    struct Leak(Rc<RefCell<Vec<Waker>>>);
+    impl Future for Leak {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            self.0.borrow_mut().push(cx.waker().clone());
+            Poll::Ready(())
+        }
+    }
+    let wakers = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for _ in 0..os::task::executor::QUEUE_SIZE {
+        executor.spawn(Task::new(Leak(wakers.clone())));
+    }
+    executor.run_until_idle();
+    // enough stale wakes to fill the queue if they went in
+    for waker in wakers.borrow().iter() {
+        waker.wake_by_ref();
+    }
+    let ran = Rc::new(RefCell::new(false));
+    let inner = ran.clone();
+    executor.spawn(Task::new(async move {
+        *inner.borrow_mut() = true;
+    }));
+    executor.run_until_idle();
+    interrupts::disable();
+    assert!(*ran.borrow());
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use common::with_interrupts;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use kern_test::kern_test;
+use os::{serial_print, serial_println, thread, timer};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    thread::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+#[kern_test]
+fn join_returns_result() {
+    with_interrupts(|| {
+        let handle = thread::spawn(|| 6 * 7).unwrap();
+        assert_ne!(handle.id(), thread::current());
+        assert_eq!(handle.join(), 42);
+    });
+}
+
+#[kern_test]
+fn busy_thread_is_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    with_interrupts(|| {
+        let handle = thread::spawn(|| {
+            let mut spins = 0u64;
+            while !STOP.load(Ordering::SeqCst) {
+                spins += 1;
+            }
+            spins
+        })
+        .unwrap();
+        // the spinning thread never yields, we only
+        // get back here if it is preempted
+        let start = timer::ticks();
+        while timer::ticks() - start < 50 {
+            thread::yield_now();
+        }
+        STOP.store(true, Ordering::SeqCst);
+        assert!(handle.join() > 0);
+    });
+}
+
+#[kern_test]
+fn sleepers_wake_in_order() {
+    with_interrupts(|| {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = [30, 10, 20]
+            .iter()
+            .map(|&ms| {
+                let order = order.clone();
+                thread::spawn(move || {
+                    thread::sleep_ms(ms);
+                    interrupts::without_interrupts(|| order.lock().push(ms));
+                })
+                .unwrap()
+            })
+            .collect();
+        for handle in handles {
+            handle.join();
+        }
+        assert_eq!(*order.lock(), [10, 20, 30]);
+    });
+}
+
+#[kern_test]
+fn many_threads_finish() {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    with_interrupts(|| {
+        for _ in 0..4 {
+            let handles: Vec<_> = (0..thread::MAX_THREADS / 2)
+                .map(|_| {
+                    thread::spawn(|| {
+                        thread::yield_now();
+                        COUNT.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .unwrap()
+                })
+                .collect();
+            handles.into_iter().for_each(thread::JoinHandle::join);
+        }
+        assert_eq!(COUNT.load(Ordering::SeqCst), 4 * (thread::MAX_THREADS / 2));
+    });
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use lazy_static::lazy_static;
+use os::{
+    exit_qemu,
+    memory::{stack, StackName},
+    serial_print, serial_println, thread, QemuExitCode,
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+/// The id of the thread that overflows
+static OVERFLOWING: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(os::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    match stack::overflowed(Cr2::read()) {
+        Some(StackName::Thread(id)) if id.as_u64() == OVERFLOWING.load(Ordering::SeqCst) => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        other => {
+            serial_println!("[failed]");
+            serial_println!("expected an overflow in the thread, got {:?}", other);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    serial_print!("thread_stack_overflow... ");
+    common::init(info, None);
+    os::gdt::init_stacks().expect("IST stack init failed");
+    thread::init();
+    // the test IDT has no timer handler
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let handle = thread::spawn(|| stack_overflow(0)).unwrap();
+    OVERFLOWING.store(handle.id().as_u64(), Ordering::SeqCst);
+    handle.join();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow(ct: usize) {
+    stack_overflow(ct + 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
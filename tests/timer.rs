@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::with_interrupts;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use kern_test::kern_test;
+    use os::{serial_print, serial_println, timer};
+
+    #[kern_test]
+    fn sleep_advances_uptime() {
+        with_interrupts(|| {
+            let before = timer::uptime();
+            timer::sleep_ms(50);
+            assert!(timer::uptime() - before >= core::time::Duration::from_millis(50));
+        });
+    }
+
+    #[kern_test]
+    fn deadline_expires() {
+        with_interrupts(|| {
+            let deadline = timer::Deadline::after_ms(20);
+            assert!(!deadline.expired());
+            timer::sleep_until(deadline);
+            assert!(deadline.expired());
+            assert_eq!(deadline.remaining(), core::time::Duration::from_millis(0));
+        });
+    }
+
+    static ONE_SHOT: AtomicUsize = AtomicUsize::new(0);
+    static PERIODIC: AtomicUsize = AtomicUsize::new(0);
+
+    #[kern_test]
+    fn one_shot_runs_once() {
+        fn cb() {
+            ONE_SHOT.fetch_add(1, Ordering::SeqCst);
+        }
+        with_interrupts(|| {
+            let id = timer::after_ms(10, cb).unwrap();
+            timer::sleep_ms(50);
+            assert_eq!(ONE_SHOT.load(Ordering::SeqCst), 1);
+            assert!(!timer::cancel(id));
+        });
+    }
+
+    #[kern_test]
+    fn periodic_runs_until_cancelled() {
+        fn cb() {
+            PERIODIC.fetch_add(1, Ordering::SeqCst);
+        }
+        with_interrupts(|| {
+            let id = timer::every_ms(5, cb).unwrap();
+            timer::sleep_ms(60);
+            assert!(timer::cancel(id));
+            let runs = PERIODIC.load(Ordering::SeqCst);
+            assert!(runs >= 5, "only ran {} times", runs);
+            timer::sleep_ms(20);
+            assert_eq!(PERIODIC.load(Ordering::SeqCst), runs);
+        });
+    }
+
+    #[kern_test]
+    fn deadline_skips_current_tick() {
+        // interrupts are off, the ticks can't move
+        let now = timer::ticks();
+        assert_eq!(timer::Deadline::after_ms(1).tick(), now + 2);
+        assert_eq!(timer::Deadline::after_ms(0).tick(), now);
+    }
+}
+
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+mod common;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kern_test::kern_test;
+use os::{serial_print, serial_println, syscall, user};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(info: &'static BootInfo) -> ! {
+    common::init(info, None);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic(info)
+}
+
+/// Copy `code` to `at` in user memory, run it
+/// and return its exit code
+fn run_code(at: u64, code: &[u8]) -> u64 {
+    let entry = VirtAddr::new(user::USER_START + at);
+    let stack = entry + 0x10000u64;
+    user::map(entry, code.len() as u64, true).unwrap();
+    user::map(stack, 4096, true).unwrap();
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), entry.as_mut_ptr(), code.len());
+        user::run(entry, stack + 4096u64)
+    }
+}
+
+#[kern_test]
+fn write_then_exit() {
+    let code = [
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, SYS_WRITE
+        0x48, 0x8d, 0x3d, 0x12, 0x00, 0x00, 0x00, // lea rdi, [rip + msg]
+        0x48, 0xc7, 0xc6, 0x12, 0x00, 0x00, 0x00, // mov rsi, 18
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+        0x0f, 0x0b, // ud2
+        b'h', b'e', b'l', b'l', b'o', b' ', b'f', b'r', b'o', b'm', b' ', b'r', b'i', b'n', b'g',
+        b' ', b'3', b'\n',
+    ];
+    assert_eq!(run_code(0, &code), 18);
+}
+
+#[kern_test]
+fn unknown_syscall() {
+    let code = [
+        0x48, 0xc7, 0xc0, 0x63, 0x00, 0x00, 0x00, // mov rax, 99
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run_code(0x100000, &code), syscall::ENOSYS);
+}
+
+#[kern_test]
+fn write_rejects_kernel_pointer() {
+    let code = [
+        0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, SYS_WRITE
+        0x48, 0xc7, 0xc7, 0x00, 0x00, 0x20, 0x00, // mov rdi, 0x200000
+        0x48, 0xc7, 0xc6, 0x04, 0x00, 0x00, 0x00, // mov rsi, 4
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ];
+    assert_eq!(run_code(0x200000, &code), syscall::EFAULT);
+}
+
+#[kern_test]
+fn map_outside_user_memory() {
+    let kernel = VirtAddr::new(os::allocator::HEAP_START as u64);
+    assert!(user::map(kernel, 4096, true).is_err());
+}